@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use crate::{
+    program::{Item, Env, Bindings, Closure, Lambda, RecGroup, BinaryOperator, BinaryComparator, UnaryOperator, BooleanOperator, Operator},
+    symbol::Symbol,
+    lexer::Span,
+    list::List,
+    error::{NmError, ErrorKind},
+    eval::{eval, f32_matherate, f32_comparate, f32_unarate}};
+
+/// Evaluates `program` once for a whole block of samples, with each of
+/// `inputs` bound to an `Item::Floats`, and writes one result per sample to
+/// `out`. Operators work on every sample of a block in one go, so the cost of
+/// interpreting the program is shared by the whole block. Every input must be
+/// as long as `out`.
+pub fn eval_block(program: &Item, inputs: &[(&str, &[f32])], env: &Env, out: &mut [f32]) -> Result<(), NmError> {
+    let frame = inputs.iter().map(|(name, samples)| (Symbol::intern(name), Item::Floats(Arc::from(*samples)))).collect();
+    match eval(program, &env.push_frame(frame))? {
+        Item::Floats(samples) if samples.len() == out.len() => out.copy_from_slice(&samples),
+        Item::Float(value) => out.fill(value),
+        Item::Number(value) => out.fill(value as f32),
+        other => return Err(NmError::new(ErrorKind::TypeMismatch(format!("a block of {} samples must evaluate to numbers, got {:?}", out.len(), other)), None))
+    }
+    Ok(())
+}
+
+/// An operand of a block operator: either one value for every sample or a
+/// value per sample.
+#[derive(Clone, Copy)]
+enum Samples<'a, T: Copy> {
+    One(T),
+    Many(&'a [T]),
+}
+
+impl<'a, T: Copy> Samples<'a, T> {
+    fn get(&self, index: usize) -> T {
+        match self {
+            Samples::One(value) => *value,
+            Samples::Many(values) => values[index],
+        }
+    }
+}
+
+fn floats(item: &Item) -> Option<Samples<'_, f32>> {
+    match item {
+        Item::Float(value) => Some(Samples::One(*value)),
+        Item::Floats(values) => Some(Samples::Many(values)),
+        _ => None
+    }
+}
+
+/// Like `floats`, but with integers promoted, for operators.
+fn numbers(item: &Item) -> Option<Samples<'_, f32>> {
+    match item {
+        Item::Number(value) => Some(Samples::One(*value as f32)),
+        other => floats(other)
+    }
+}
+
+fn bools(item: &Item) -> Option<Samples<'_, bool>> {
+    match item {
+        Item::Boolean(value) => Some(Samples::One(*value)),
+        Item::Bools(values) => Some(Samples::Many(values)),
+        _ => None
+    }
+}
+
+pub fn is_block(item: &Item) -> bool {
+    matches!(item, Item::Floats(_) | Item::Bools(_))
+}
+
+/// Applies `func` to each pair of samples of `a` and `b`.
+fn zip<T: Copy, U>(a: Samples<T>, b: Samples<T>, func: impl Fn(T, T) -> U, span: Span) -> Result<Arc<[U]>, NmError> {
+    let len = match (a, b) {
+        (Samples::Many(a), Samples::Many(b)) if a.len() != b.len() => {
+            return Err(NmError::type_mismatch(format!("blocks of {} and {} samples", a.len(), b.len()), span))
+        },
+        (Samples::Many(values), _) | (_, Samples::Many(values)) => values.len(),
+        (Samples::One(_), Samples::One(_)) => 1,
+    };
+    Ok((0..len).map(|index| func(a.get(index), b.get(index))).collect())
+}
+
+fn mismatch(operands: &[Item], op: Operator, span: Span) -> NmError {
+    NmError::type_mismatch(format!("arguments {:?} not suitable for {} on a block", operands, op), span)
+}
+
+/// Applies `op` sample by sample. At least one operand is a block; the others
+/// are used for every sample.
+pub(crate) fn apply_operator(op: &Operator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match op {
+        Operator::BinaryOperator(binop) => operate(binop, operands, span),
+        Operator::BinaryComparator(bincomp) => comparate(bincomp, operands, span),
+        Operator::UnaryOperator(unop) => unarate(unop, operands, span),
+        Operator::BooleanOperator(boolop) => boolerate(boolop, operands, span),
+    }
+}
+
+fn operate(op: &BinaryOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match (numbers(&operands[0]), numbers(&operands[1])) {
+        (Some(a), Some(b)) => Ok(Item::Floats(zip(a, b, f32_matherate(op), span)?)),
+        _ => Err(mismatch(operands, Operator::BinaryOperator(op.clone()), span))
+    }
+}
+
+fn comparate(op: &BinaryComparator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match (numbers(&operands[0]), numbers(&operands[1])) {
+        (Some(a), Some(b)) => Ok(Item::Bools(zip(a, b, f32_comparate(op), span)?)),
+        _ => Err(mismatch(operands, Operator::BinaryComparator(op.clone()), span))
+    }
+}
+
+fn unarate(op: &UnaryOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match &operands[0] {
+        Item::Floats(values) => {
+            let func = f32_unarate(op);
+            Ok(Item::Floats(values.iter().map(|value| func(*value)).collect()))
+        },
+        _ => Err(mismatch(operands, Operator::UnaryOperator(op.clone()), span))
+    }
+}
+
+fn boolerate(op: &BooleanOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    let values = match (op, bools(&operands[0]), operands.get(1).and_then(bools)) {
+        (BooleanOperator::Not, Some(a), _) => zip(a, a, |a, _| !a, span)?,
+        (BooleanOperator::And, Some(a), Some(b)) => zip(a, b, |a, b| a && b, span)?,
+        (BooleanOperator::Or, Some(a), Some(b)) => zip(a, b, |a, b| a || b, span)?,
+        _ => return Err(mismatch(operands, Operator::BooleanOperator(op.clone()), span))
+    };
+    Ok(Item::Bools(values))
+}
+
+/// `item` restricted to the samples at `lanes`, if it is a block or a
+/// function that captured one.
+fn gather(item: &Item, lanes: &[usize]) -> Item {
+    match item {
+        Item::Floats(values) => Item::Floats(lanes.iter().map(|lane| values[*lane]).collect()),
+        Item::Bools(values) => Item::Bools(lanes.iter().map(|lane| values[*lane]).collect()),
+        Item::Function(closure) => Item::Function(gather_closure(closure, lanes)),
+        other => other.clone()
+    }
+}
+
+/// `closure` with the blocks in its captured frames, and in those of the
+/// functions it was bound with, restricted to `lanes`.
+fn gather_closure(closure: &Closure, lanes: &[usize]) -> Closure {
+    let lambda = |lambda: &Lambda| Arc::new(Lambda {
+        name: lambda.name,
+        params: lambda.params.clone(),
+        body: lambda.body.clone(),
+        env: gather_locals(&lambda.env, lanes),
+        code: lambda.code.clone(),
+    });
+    let group = closure.group.as_ref().map(|group| Arc::new(RecGroup { members: group.members.iter().map(|member| lambda(member)).collect() }));
+    Closure { lambda: lambda(&closure.lambda), group }
+}
+
+fn gather_locals(locals: &Bindings, lanes: &[usize]) -> Bindings {
+    let frames = locals.iter().collect::<Vec<_>>();
+    frames.into_iter().rev().fold(List::new(), |locals, frame| {
+        locals.prepend(frame.iter().map(|(name, value)| (*name, gather(value, lanes))).collect())
+    })
+}
+
+/// `env` with every block bound in its frames restricted to `lanes`.
+fn gather_env(env: &Env, lanes: &[usize]) -> Env {
+    env.with_locals(gather_locals(&env.locals, lanes))
+}
+
+/// Evaluates an `if` whose condition is a block: each branch runs for the
+/// samples that take it, so a recursive function stops once every sample has
+/// reached its base case. The branches must both give numbers, with integers
+/// promoted to floats, or both give booleans.
+pub fn select(condition: &[bool], then: &Item, otherwise: &Item, env: &Env, span: Span) -> Result<Item, NmError> {
+    let (then_lanes, else_lanes): (Vec<usize>, Vec<usize>) = (0..condition.len()).partition(|lane| condition[*lane]);
+    if else_lanes.is_empty() {
+        return eval(then, env);
+    }
+    if then_lanes.is_empty() {
+        return eval(otherwise, env);
+    }
+    let then_value = eval(then, &gather_env(env, &then_lanes))?;
+    let else_value = eval(otherwise, &gather_env(env, &else_lanes))?;
+
+    if let (Some(a), Some(b)) = (numbers(&then_value), numbers(&else_value)) {
+        return Ok(Item::Floats(scatter(condition.len(), [(&then_lanes, a), (&else_lanes, b)], span)?));
+    }
+    if let (Some(a), Some(b)) = (bools(&then_value), bools(&else_value)) {
+        return Ok(Item::Bools(scatter(condition.len(), [(&then_lanes, a), (&else_lanes, b)], span)?));
+    }
+    Err(NmError::type_mismatch(format!("if branches over a block must both be numbers or both booleans, got {:?} and {:?}", then_value, else_value), span))
+}
+
+/// Puts the samples each branch of an `if` gave back in the lanes they came
+/// from.
+fn scatter<T: Copy + Default>(len: usize, branches: [(&[usize], Samples<T>); 2], span: Span) -> Result<Arc<[T]>, NmError> {
+    let mut samples = vec![T::default(); len];
+    for (lanes, values) in branches {
+        if let Samples::Many(values) = values {
+            if values.len() != lanes.len() {
+                return Err(NmError::type_mismatch(format!("if branch gave a block of {} samples for {}", values.len(), lanes.len()), span));
+            }
+        }
+        for (index, lane) in lanes.iter().enumerate() {
+            samples[*lane] = values.get(index);
+        }
+    }
+    Ok(samples.into())
+}
@@ -1,137 +1,304 @@
-use std::io;
+use std::{collections::HashSet, io, sync::Arc};
 
-use crate::{program::{Item, Builtin}, list::List, eval::eval};
+use crate::{program::{Item, Builtin, Lambda, Closure, RecGroup, Env, Frame, Params}, list::List, eval::{eval, bind_group, Step}, lexer::Span, error::{NmError, ErrorKind}, symbol::Symbol, compiler::BodyCode, resolver::replace_head, block};
 
-pub fn builtinerate<'a>(builtin: &Builtin, list: &List<Item>, env: &List<(&str, Item)>) -> Result<Item, String> {
-    match builtin { Builtin::Func => {
+/// Reads a parameter list such as `(a b &optional (c 1) d &rest more)`.
+/// Optional parameters written without a default get `nil`.
+pub(crate) fn parse_params(list: &List<Item>, span: Span) -> Result<Params, NmError> {
+    let mut params = Params { required: Vec::new(), optional: Vec::new(), rest: None };
+    let mut optional = false;
+    let mut items = list.iter();
+    while let Some(item) = items.next() {
+        match item {
+            Item::Identifier(name, _) if *name == *"&optional" && !optional => optional = true,
+            Item::Identifier(name, _) if *name == *"&rest" => {
+                match (items.next(), items.next()) {
+                    (Some(Item::Identifier(rest, _)), None) => params.rest = Some(*rest),
+                    _ => return Err(NmError::syntax(String::from("&rest must be followed by exactly one parameter name"), span))
+                }
+            },
+            Item::Identifier(name, _) if name.as_str().starts_with('&') => {
+                return Err(NmError::syntax(format!("unexpected {} in parameter list", name), span))
+            },
+            Item::Identifier(name, _) if optional => params.optional.push((*name, Item::Nil)),
+            Item::Identifier(name, _) => params.required.push(*name),
+            Item::List(default, _) if optional => {
+                match (default.car(), default.cdr().car(), default.cdr().cdr().car()) {
+                    (Some(Item::Identifier(name, _)), Some(value), None) => params.optional.push((*name, value.clone())),
+                    _ => return Err(NmError::syntax(String::from("optional parameter must be a name or (name default)"), span))
+                }
+            },
+            other => return Err(NmError::syntax(format!("expected parameter name, got {:?}", other), span))
+        }
+    }
+    let mut seen = HashSet::new();
+    let optional = params.optional.iter().map(|(name, _)| name);
+    if let Some(name) = params.required.iter().chain(optional).chain(&params.rest).find(|name| !seen.insert(**name)) {
+        return Err(NmError::syntax(format!("parameter {} is named more than once", name), span));
+    }
+    Ok(params)
+}
+
+/// The parameter list and body of a `(func (args) body)` form, if `item` is one.
+pub(crate) fn func_parts(item: &Item) -> Option<(List<Item>, Span, Item)> {
+    match item {
+        Item::List(list, _) if matches!(list.car(), Some(Item::Builtin(Builtin::Func))) => {
+            match (list.cdr().car(), list.cdr().cdr().car()) {
+                (Some(Item::List(params, span)), Some(body)) => Some((params.clone(), *span, body.clone())),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
+
+/// A `(name value)` binding in a `let`.
+pub(crate) struct Binding {
+    pub name: Symbol,
+    pub value: Item,
+    /// The binding as it was written, and where.
+    pub list: List<Item>,
+    pub span: Span,
+}
+
+impl Binding {
+    /// The binding as written, with its value replaced by `value`.
+    pub fn with_value(&self, value: Item) -> Item {
+        let name = self.list.car().cloned().unwrap_or(Item::Nil);
+        Item::List(replace_head(vec![name, value], &self.list, 2), self.span)
+    }
+}
+
+/// A function bound by a `let`, with its parameters read.
+pub(crate) struct LetFunction {
+    pub binding: Binding,
+    pub params: Params,
+    pub body: Item,
+}
+
+/// A frame a `let` adds to the environment.
+pub(crate) enum LetFrame {
+    /// Bindings whose values are all evaluated, with the frames before them,
+    /// before any of them is bound.
+    Values(Vec<Binding>),
+    /// A run of adjacent functions, which share a frame so that each of them
+    /// can call itself and the others.
+    Group(Vec<LetFunction>),
+}
+
+/// Splits the arguments of a `let` or `letrec` into the frames it adds, in
+/// order, and its body. A `let` binds everything in one frame, with each
+/// value seeing none of the other bindings. In a `letrec` each binding gets a
+/// frame of its own and sees the bindings before it, except that adjacent
+/// functions share a frame. Every pass that handles `let` builds its frames
+/// from these, so they all agree on where each name is bound.
+pub(crate) fn let_frames(builtin: &Builtin, args: &[Item], span: Span) -> Result<(Vec<LetFrame>, Item), NmError> {
+    let Some((body, bindings)) = args.split_last() else {
+        return Err(NmError::syntax(format!("{} has no body", builtin), span));
+    };
+    let mut frames = Vec::new();
+    for item in bindings {
+        let Item::List(list, binding_span) = item else {
+            return Err(NmError::syntax(format!("expected list after {}", builtin), span));
+        };
+        let Some(Item::Identifier(name, _)) = list.car() else {
+            return Err(NmError::syntax(format!("expected identifier in {}", builtin), *binding_span));
+        };
+        let Some(value) = list.cdr().car().cloned() else {
+            return Err(NmError::syntax(format!("missing value in {} binding", builtin), *binding_span));
+        };
+        let binding = Binding { name: *name, value, list: list.clone(), span: *binding_span };
+        if !matches!(builtin, Builtin::Letrec) {
+            match frames.last_mut() {
+                Some(LetFrame::Values(values)) => values.push(binding),
+                _ => frames.push(LetFrame::Values(vec![binding]))
+            }
+            continue;
+        }
+        let Some((params, params_span, body)) = func_parts(&binding.value) else {
+            frames.push(LetFrame::Values(vec![binding]));
+            continue;
+        };
+        let function = LetFunction { binding, params: parse_params(&params, params_span)?, body };
+        match frames.last_mut() {
+            Some(LetFrame::Group(run)) => run.push(function),
+            _ => frames.push(LetFrame::Group(vec![function]))
+        }
+    }
+    Ok((frames, body.clone()))
+}
+
+/// Closes a run of `let`-bound functions over `env` and binds them, so that
+/// every function in the run can call itself and the others.
+fn bind_run(env: Env, run: Vec<LetFunction>) -> Env {
+    let members = run.into_iter()
+        .map(|function| Arc::new(Lambda { name: Some(function.binding.name), params: function.params, body: function.body, env: env.locals.clone(), code: BodyCode::default() }))
+        .collect();
+    env.with_locals(bind_group(&env.locals, &Arc::new(RecGroup { members })))
+}
+
+/// Gives an anonymous function the name it is being bound to, for backtraces.
+pub(crate) fn named(value: Item, name: Symbol) -> Item {
+    match value {
+        Item::Function(closure) if closure.lambda.name.is_none() => {
+            let lambda = &closure.lambda;
+            Item::Function(Closure {
+                lambda: Arc::new(Lambda {
+                    name: Some(name),
+                    params: lambda.params.clone(),
+                    body: lambda.body.clone(),
+                    env: lambda.env.clone(),
+                    code: lambda.code.clone(),
+                }),
+                group: closure.group.clone(),
+            })
+        },
+        value => value
+    }
+}
+
+/// Runs a builtin. `if`, `let` and `progn` hand back the expression in tail
+/// position as a [`Step::Tail`] rather than evaluating it themselves.
+pub fn builtinerate(builtin: &Builtin, list: &List<Item>, span: Span, env: &Env) -> Result<Step, NmError> {
+    match builtin {
+        Builtin::Func => {
             match (list.car(), list.cdr().car()) {
-                (Some(Item::List(args)), Some(item)) => {
-                    Ok(Item::Function(args.clone(), Box::new(item.clone())))
+                (Some(Item::List(args, args_span)), Some(item)) => {
+                    let lambda = Lambda { name: None, params: parse_params(args, *args_span)?, body: item.clone(), env: env.locals.clone(), code: BodyCode::default() };
+                    Ok(Step::Done(Item::Function(Closure { lambda: Arc::new(lambda), group: None })))
                 }
-                _ => Err(format!("Function needs (args) (eval)"))
+                _ => Err(NmError::syntax(String::from("func needs (args) (eval)"), span))
             }
         },
         Builtin::Progn => {
-            let mut cursor = list.iter();
-            let mut last_eval = None;
-            while let Some(item) = cursor.next() {
-                match eval(item, env) {
-                    Ok(evaluated) => last_eval = Some(evaluated),
-                    Err(msg) => return Err(msg)
+            let mut items = list.iter().peekable();
+            while let Some(item) = items.next() {
+                if items.peek().is_none() {
+                    return Ok(Step::Tail(item.clone(), env.clone()));
                 }
+                eval(item, env)?;
             }
-            match last_eval {
-                Some(result) => Ok(result),
-                None => Err(format!("Error, progn has no programs in it!"))
-            }
+            // a file that is empty or only has comments in it
+            Ok(Step::Done(Item::Nil))
         },
         Builtin::Print => {
             match list.car() {
                 Some(item) => {
-                    match eval(item, env) {
-                        Ok(Item::String(printout)) => {
-                            println!("{}", printout);
-                            Ok(Item::Nil)
-                        },
-                        Ok(res) => {
-                            println!("{:?}", res);
-                            Ok(Item::Nil)
-                        },
-                        //Ok(_) => Err(format!("Error: Print must be followed by a string!")),
-                        Err(msg) => Err(msg)
+                    match eval(item, env)? {
+                        Item::String(printout) => println!("{}", printout),
+                        res => println!("{:?}", res),
                     }
+                    Ok(Step::Done(Item::Nil))
                 },
-                _ => Err(format!("Error: Print must be followed by a string!"))
+                _ => Err(NmError::arity(String::from("print"), 1, Some(1), 0, span))
             }
         },
-        Builtin::Let => {
-            let mut args = list.iter().peekable();
+        Builtin::Let | Builtin::Letrec => {
+            let args = list.iter().cloned().collect::<Vec<Item>>();
+            let (frames, body) = let_frames(builtin, &args, span)?;
             let mut new_env = env.clone();
-            while let Some(item) = args.next() {
-                if (args.peek().is_none()) {
-                    //this is the last one, eval it
-                    return eval(item, &new_env);
-                }
-                else {
-                    match (item) {
-                        Item::List(let_list) => {
-                            let name = let_list.car();
-
-                            let cdr = let_list.cdr();
-                            let value = cdr.car();
-                            let value_eval = match value {
-                                Some(val) => val,
-                                None => return Err(format!("Missing value in let for variable {:?}", name))
-                            };
-
-                            match (name, eval(value_eval, env)) {
-                                (Some(Item::Identifier(item_name)), Ok(result)) => {
-                                    new_env = new_env.prepend((item_name.as_str(), result))
-                                },
-                                (Some(_), _) => return Err(format!("Expected identifier in let!")),
-                                (_, Err(msg)) => return Err(msg),
-                                _ => return Err(format!("Uncategorizable error in let"))
-                            }
-                        }
-                        _ => return Err(format!("Error, expected list after let"))
-                    }
-                }
+            for frame in frames {
+                new_env = match frame {
+                    LetFrame::Values(bindings) => {
+                        let frame = bindings.into_iter()
+                            .map(|binding| Ok((binding.name, named(eval(&binding.value, &new_env)?, binding.name))))
+                            .collect::<Result<Frame, NmError>>()?;
+                        new_env.push_frame(frame)
+                    },
+                    LetFrame::Group(run) => bind_run(new_env, run)
+                };
             }
-            Err(format!("Error, couldn't evaluate let"))
+            Ok(Step::Tail(body, new_env))
         },
         Builtin::If => {
             let condition = match list.car() {
-                Some(item) => match eval(item, env) {
-                    Ok(result) => result,
-                    Err(msg) => return Err(msg)
-                },
-                _ => return Err(format!("Error: if must be followed by condition"))
+                Some(item) => eval(item, env)?,
+                _ => return Err(NmError::syntax(String::from("if must be followed by condition"), span))
             };
             match condition {
                 Item::Boolean(true) => {
                     match list.cdr().car() {
-                        Some(item) => eval(item, env),
-                        _ => Err(format!("Error: if must contain statement for true evaluation."))
+                        Some(item) => Ok(Step::Tail(item.clone(), env.clone())),
+                        _ => Err(NmError::syntax(String::from("if must contain statement for true evaluation"), span))
                     }
                 },
                 Item::Boolean(false) => {
                     match list.cdr().cdr().car() {
-                        Some(item) => eval(item, env),
-                        _ => Err(format!("Error: if must contain statement for false evaluation."))
+                        Some(item) => Ok(Step::Tail(item.clone(), env.clone())),
+                        _ => Err(NmError::syntax(String::from("if must contain statement for false evaluation"), span))
                     }
                 },
-                _ => Err(format!("Error, if condition must be a boolean"))
+                Item::Bools(condition) => match (list.cdr().car(), list.cdr().cdr().car()) {
+                    (Some(then), Some(otherwise)) => block::select(&condition, then, otherwise, env, span).map(Step::Done),
+                    _ => Err(NmError::syntax(String::from("if on a block must contain statements for true and false evaluation"), span))
+                },
+                other => Err(NmError::type_mismatch(format!("if condition must be a boolean, got {:?}", other), span))
             }
         },
         Builtin::Input => {
             let mut buffer = String::new();
-            let stdin = io::stdin();
-            stdin.read_line(&mut buffer);
-            Ok(Item::String(buffer))
+            io::stdin().read_line(&mut buffer).map_err(|err| {
+                let mut err = NmError::from(err);
+                err.span = Some(span);
+                err
+            })?;
+            Ok(Step::Done(Item::String(buffer)))
         },
         Builtin::Cat => {
-            let cdr = list.cdr();
-            let string1 = match list.car() {
-                Some(item) => match eval(item, env) {
-                    Ok(Item::String(result)) => result,
-                    Ok(result) => return Err(format!("cat takes two strings as an argument! {:?} was supplied", result)),
-                    Err(msg) => return Err(msg)
+            let strings = list.iter().take(2).map(|item| match eval(item, env)? {
+                Item::String(result) => Ok(result),
+                result => Err(NmError::type_mismatch(format!("cat takes two strings as an argument! {:?} was supplied", result), span))
+            }).collect::<Result<Vec<String>, NmError>>()?;
+            if strings.len() < 2 {
+                return Err(NmError::arity(String::from("cat"), 2, Some(2), strings.len(), span));
+            }
+            Ok(Step::Done(Item::String(format!("{}{}", strings[0], strings[1]))))
+        },
+        Builtin::Error => {
+            let message = list.iter().map(|item| eval(item, env).map(|value| match value {
+                Item::String(s) => s,
+                other => format!("{:?}", other)
+            })).collect::<Result<Vec<String>, NmError>>()?;
+            Err(NmError::new(ErrorKind::User(message.join(" ")), Some(span)))
+        },
+        Builtin::Define => {
+            // (define name value) or (define (name params...) body), always
+            // binding in the global environment.
+            let (name, value) = match (list.car(), list.cdr().car()) {
+                (Some(Item::Identifier(name, _)), Some(value)) => (*name, named(eval(value, env)?, *name)),
+                (Some(Item::List(signature, signature_span)), Some(body)) => {
+                    let name = match signature.car() {
+                        Some(Item::Identifier(name, _)) => *name,
+                        _ => return Err(NmError::syntax(String::from("define needs a function name"), *signature_span))
+                    };
+                    let lambda = Lambda {
+                        name: Some(name),
+                        params: parse_params(&signature.cdr(), *signature_span)?,
+                        body: body.clone(),
+                        env: env.locals.clone(),
+                        code: BodyCode::default(),
+                    };
+                    (name, Item::Function(Closure { lambda: Arc::new(lambda), group: None }))
                 },
-                _ => return Err(format!("Error: cat missing first argument"))
+                _ => return Err(NmError::syntax(String::from("define needs a name and a value"), span))
             };
-            let string2 = match cdr.car() {
-                Some(item) => match eval(item, env) {
-                    Ok(Item::String(result)) => result,
-                    Ok(result) => return Err(format!("cat takes two strings as an argument! {:?} was supplied", result)),
-                    Err(msg) => return Err(msg)
-                },
-                _ => {
-                    println!("string1 {}", string1);
-                    return Err(format!("Error: cat missing second argument"))
-                }
+            env.define(name, value);
+            Ok(Step::Done(Item::Nil))
+        },
+        Builtin::Set => {
+            let rest = list.cdr();
+            let (name, name_span, value) = match (list.car(), rest.car()) {
+                (Some(Item::Identifier(name, name_span)), Some(value)) => (*name, *name_span, value),
+                _ => return Err(NmError::syntax(String::from("set! needs a name and a value"), span))
             };
-            Ok(Item::String(format!("{}{}", string1, string2)))
+            if env.is_local(name) {
+                return Err(NmError::syntax(format!("set! can only change globals, {} is a local binding", name), name_span));
+            }
+            let value = eval(value, env)?;
+            if !env.set(name, value) {
+                return Err(NmError::unbound(name.as_str(), name_span));
+            }
+            Ok(Step::Done(Item::Nil))
         },
-        _ => Err(format!("builtin not implemented yet"))
     }
-}
\ No newline at end of file
+}
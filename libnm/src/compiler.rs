@@ -0,0 +1,295 @@
+use std::sync::{Arc, OnceLock};
+
+use crate::{
+    program::{Item, Builtin, Operator, BooleanOperator, Params, Address},
+    list::List,
+    symbol::Symbol,
+    lexer::Span,
+    builtins::{func_parts, parse_params, let_frames, LetFrame}};
+
+/// A function body compiled for the VM. It is compiled the first time the VM
+/// calls the function, and shared by every closure made from the same `func`.
+pub type BodyCode = Arc<OnceLock<Arc<Chunk>>>;
+
+/// One VM instruction. Instructions work on a stack of values and on the
+/// environment of the function that is running.
+#[derive(Clone, Debug)]
+pub enum Op {
+    /// Pushes `constants[i]`.
+    Const(usize),
+    Local(Symbol, Address),
+    Global(Symbol, usize),
+    /// Looks a name up by searching the environment, for identifiers the
+    /// resolver left alone.
+    Name(Symbol),
+    Pop,
+    /// Applies an operator to the top `n` values.
+    Operator(Operator, usize),
+    /// Calls the value below the top `n` values with them as arguments. The
+    /// symbol is the name the function was called by, for backtraces.
+    Call(usize, Option<Symbol>),
+    /// A call in tail position, which replaces the running function.
+    TailCall(usize, Option<Symbol>),
+    Return,
+    Jump(usize),
+    /// Jumps, leaving the top value where it is, if it settles the result of
+    /// the `and` or `or` being applied, which then skips its other operands.
+    JumpIfDecided(usize, Operator),
+    /// Pops the condition of an `if` and jumps if it is false. A block of
+    /// booleans is instead handed to `block::select` with the branches in
+    /// `constants[i]`, after which the code carries on from the `Jump` that
+    /// ends the true branch, just before the target.
+    JumpUnless(usize, usize),
+    /// Pushes a closure made from `templates[i]`.
+    Closure(usize),
+    /// Binds `templates[start..end]` in one frame, each able to call the others.
+    Group(usize, usize),
+    /// Pops a value for each name and binds them all in a new frame.
+    Bind(Vec<Symbol>),
+    /// Drops the innermost `n` frames once a `let` is done with them.
+    Unbind(usize),
+    Print,
+    Input,
+    /// Fails unless the top value is a string, which `cat` needs.
+    CheckString,
+    Cat,
+    /// Pops `n` values and fails with them as the message.
+    Raise(usize),
+    Define(Symbol),
+    /// Fails if the name is bound locally, before `set!` evaluates its value.
+    CheckGlobal(Symbol),
+    Set(Symbol),
+    /// Evaluates `constants[i]` with `eval`, for forms too malformed to compile.
+    /// `eval` reports them the same way it always has.
+    Eval(usize),
+}
+
+/// A `func` form the VM makes closures from.
+#[derive(Debug)]
+pub struct Template {
+    pub name: Option<Symbol>,
+    pub params: Params,
+    pub body: Item,
+    pub code: BodyCode,
+}
+
+/// Compiled code: instructions, the location each came from and the values
+/// they refer to.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Item>,
+    pub templates: Vec<Template>,
+}
+
+/// Compiles a resolved program, or the body of a function, into a chunk that
+/// leaves its value on the stack and returns.
+pub fn compile(program: &Item) -> Chunk {
+    let mut compiler = Compiler { chunk: Chunk::default() };
+    compiler.item(program, Span::default(), true);
+    compiler.emit(Op::Return, Span::default());
+    compiler.chunk
+}
+
+struct Compiler {
+    chunk: Chunk,
+}
+
+/// The name a function is called by, if the call names it.
+fn head_name(head: &Item) -> Option<Symbol> {
+    match head {
+        Item::Identifier(name, _) => Some(*name),
+        _ => None
+    }
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.spans.push(span);
+        self.chunk.code.len() - 1
+    }
+
+    fn constant(&mut self, item: Item) -> usize {
+        self.chunk.constants.push(item);
+        self.chunk.constants.len() - 1
+    }
+
+    fn template(&mut self, name: Option<Symbol>, params: Params, body: Item) -> usize {
+        self.chunk.templates.push(Template { name, params, body, code: BodyCode::default() });
+        self.chunk.templates.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.chunk.code.len();
+        match &mut self.chunk.code[at] {
+            Op::Jump(to) | Op::JumpUnless(to, _) | Op::JumpIfDecided(to, _) => *to = target,
+            _ => unreachable!("only jumps are patched")
+        }
+    }
+
+    fn fallback(&mut self, item: Item, span: Span) {
+        let index = self.constant(item);
+        self.emit(Op::Eval(index), span);
+    }
+
+    /// Compiles `item`, which is in tail position if `tail` is set. `span` is
+    /// used for items that do not carry a location of their own.
+    fn item(&mut self, item: &Item, span: Span, tail: bool) {
+        match item {
+            Item::Identifier(name, span) => { self.emit(Op::Name(*name), *span); },
+            Item::Local(name, address, span) => { self.emit(Op::Local(*name, *address), *span); },
+            Item::Global(name, slot, span) => { self.emit(Op::Global(*name, *slot), *span); },
+            Item::List(list, span) => self.list(item, list, *span, tail),
+            _ => {
+                let index = self.constant(item.clone());
+                self.emit(Op::Const(index), span);
+            }
+        }
+    }
+
+    fn list(&mut self, item: &Item, list: &List<Item>, span: Span, tail: bool) {
+        let Some(head) = list.car() else {
+            let index = self.constant(Item::Nil);
+            self.emit(Op::Const(index), span);
+            return;
+        };
+        match head {
+            Item::Builtin(builtin) => self.builtin(builtin, item, list, span, tail),
+            Item::Operator(op @ Operator::BooleanOperator(BooleanOperator::And | BooleanOperator::Or)) => {
+                // each operand is folded into the result as soon as it is
+                // known, so that the rest can be skipped
+                let start = self.constant(Item::Boolean(*op == Operator::BooleanOperator(BooleanOperator::And)));
+                self.emit(Op::Const(start), span);
+                let mut skips = Vec::new();
+                for operand in list.cdr().iter() {
+                    skips.push(self.emit(Op::JumpIfDecided(0, op.clone()), span));
+                    self.item(operand, span, false);
+                    self.emit(Op::Operator(op.clone(), 2), span);
+                }
+                for skip in skips {
+                    self.patch(skip);
+                }
+            },
+            Item::Operator(op) => {
+                let operands = list.cdr().iter().cloned().collect::<Vec<Item>>();
+                for operand in operands.iter() {
+                    self.item(operand, span, false);
+                }
+                self.emit(Op::Operator(op.clone(), operands.len()), span);
+            },
+            _ => {
+                self.item(head, span, false);
+                let args = list.cdr();
+                for arg in args.iter() {
+                    self.item(arg, span, false);
+                }
+                let count = args.iter().count();
+                let op = if tail { Op::TailCall(count, head_name(head)) } else { Op::Call(count, head_name(head)) };
+                self.emit(op, span);
+            }
+        }
+    }
+
+    fn builtin(&mut self, builtin: &Builtin, item: &Item, list: &List<Item>, span: Span, tail: bool) {
+        let args = list.cdr().iter().cloned().collect::<Vec<Item>>();
+        match (builtin, args.as_slice()) {
+            (Builtin::Func, [Item::List(params, params_span), body, ..]) => {
+                let Ok(params) = parse_params(params, *params_span) else {
+                    return self.fallback(item.clone(), span);
+                };
+                let index = self.template(None, params, body.clone());
+                self.emit(Op::Closure(index), span);
+            },
+            (Builtin::Progn, []) => {
+                let index = self.constant(Item::Nil);
+                self.emit(Op::Const(index), span);
+            },
+            (Builtin::Progn, [forms @ .., last]) => {
+                for form in forms {
+                    self.item(form, span, false);
+                    self.emit(Op::Pop, span);
+                }
+                self.item(last, span, tail);
+            },
+            (Builtin::Print, [value, ..]) => {
+                self.item(value, span, false);
+                self.emit(Op::Print, span);
+            },
+            (Builtin::Let | Builtin::Letrec, _) => self.let_form(builtin, item, &args, span, tail),
+            (Builtin::If, [condition, then, otherwise, ..]) => {
+                self.item(condition, span, false);
+                let branches = self.constant(Item::ListLiteral(List::new().prepend(otherwise.clone()).prepend(then.clone())));
+                let skip_then = self.emit(Op::JumpUnless(0, branches), span);
+                self.item(then, span, tail);
+                let skip_else = self.emit(Op::Jump(0), span);
+                self.patch(skip_then);
+                self.item(otherwise, span, tail);
+                self.patch(skip_else);
+            },
+            (Builtin::Input, _) => { self.emit(Op::Input, span); },
+            (Builtin::Cat, [first, second, ..]) => {
+                for string in [first, second] {
+                    self.item(string, span, false);
+                    self.emit(Op::CheckString, span);
+                }
+                self.emit(Op::Cat, span);
+            },
+            (Builtin::Error, message) => {
+                for part in message {
+                    self.item(part, span, false);
+                }
+                self.emit(Op::Raise(message.len()), span);
+            },
+            (Builtin::Define, [Item::Identifier(name, _), value, ..]) => {
+                self.item(value, span, false);
+                self.emit(Op::Define(*name), span);
+            },
+            (Builtin::Define, [Item::List(signature, signature_span), body, ..]) => {
+                let (Some(Item::Identifier(name, _)), Ok(params)) = (signature.car(), parse_params(&signature.cdr(), *signature_span)) else {
+                    return self.fallback(item.clone(), span);
+                };
+                let index = self.template(Some(*name), params, body.clone());
+                self.emit(Op::Closure(index), span);
+                self.emit(Op::Define(*name), span);
+            },
+            (Builtin::Set, [Item::Identifier(name, name_span), value, ..]) => {
+                self.emit(Op::CheckGlobal(*name), *name_span);
+                self.item(value, span, false);
+                self.emit(Op::Set(*name), *name_span);
+            },
+            _ => self.fallback(item.clone(), span)
+        }
+    }
+
+    /// Binds each frame the `let` adds, then compiles its body.
+    fn let_form(&mut self, builtin: &Builtin, item: &Item, args: &[Item], span: Span, tail: bool) {
+        let Ok((frames, body)) = let_frames(builtin, args, span) else {
+            return self.fallback(item.clone(), span);
+        };
+        let count = frames.len();
+        for frame in frames {
+            match frame {
+                LetFrame::Values(bindings) => {
+                    for binding in bindings.iter() {
+                        self.item(&binding.value, span, false);
+                    }
+                    self.emit(Op::Bind(bindings.iter().map(|binding| binding.name).collect()), span);
+                },
+                LetFrame::Group(run) => {
+                    let start = self.chunk.templates.len();
+                    for function in run {
+                        self.template(Some(function.binding.name), function.params, function.body);
+                    }
+                    self.emit(Op::Group(start, self.chunk.templates.len()), span);
+                }
+            }
+        }
+        self.item(&body, span, tail);
+        if !tail && count > 0 {
+            self.emit(Op::Unbind(count), span);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{fmt, io};
+
+use crate::{lexer::{LexError, Span}, program::Item};
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Lex(LexError),
+    Parse(String),
+    /// A special form such as `let` or `func` written with the wrong shape.
+    Syntax(String),
+    Unbound(String),
+    TypeMismatch(String),
+    Arity { function: String, min: usize, max: Option<usize>, given: usize },
+    DivisionByZero,
+    /// Integer arithmetic whose result does not fit in 64 bits.
+    Overflow(String),
+    Io(io::Error),
+    /// Raised from nm code with `(error ...)`.
+    User(String),
+    /// Valid nm that a compiled signal cannot express.
+    Unsupported(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Lex(err) => f.write_str(&err.description()),
+            ErrorKind::Parse(msg) => write!(f, "Parse error: {}", msg),
+            ErrorKind::Syntax(msg) => write!(f, "Syntax error: {}", msg),
+            ErrorKind::Unbound(name) => write!(f, "Identifier not found: {}", name),
+            ErrorKind::TypeMismatch(msg) => write!(f, "Type error: {}", msg),
+            ErrorKind::Arity { function, min, max, given } => {
+                let plural = |n: usize| if n == 1 { "" } else { "s" };
+                match max {
+                    Some(max) if max == min => write!(f, "{} expects {} argument{}", function, min, plural(*min))?,
+                    Some(max) => write!(f, "{} expects {} to {} arguments", function, min, max)?,
+                    None => write!(f, "{} expects at least {} argument{}", function, min, plural(*min))?,
+                }
+                write!(f, ", {} given", given)
+            },
+            ErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            ErrorKind::Overflow(msg) => write!(f, "Integer overflow: {}", msg),
+            ErrorKind::Io(err) => write!(f, "IO error: {}", err),
+            ErrorKind::User(msg) => write!(f, "{}", msg),
+            ErrorKind::Unsupported(msg) => write!(f, "Not supported in a signal: {}", msg),
+        }
+    }
+}
+
+/// A function call that was still running when an error was raised.
+#[derive(Clone, Debug)]
+pub struct StackFrame {
+    pub function: String,
+    pub args: Vec<Item>,
+    pub span: Span,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}", self.function)?;
+        for arg in self.args.iter() {
+            write!(f, " {:?}", arg)?;
+        }
+        write!(f, ") at {}", self.span)
+    }
+}
+
+/// Everything that can go wrong while lexing, parsing or evaluating nm code.
+/// `trace` lists the calls the error unwound through, innermost first.
+#[derive(Debug)]
+pub struct NmError {
+    pub kind: ErrorKind,
+    pub span: Option<Span>,
+    pub trace: Vec<StackFrame>,
+}
+
+impl NmError {
+    pub fn new(kind: ErrorKind, span: Option<Span>) -> Self {
+        NmError { kind, span, trace: Vec::new() }
+    }
+
+    pub fn parse(msg: String, span: Span) -> Self {
+        NmError::new(ErrorKind::Parse(msg), Some(span))
+    }
+
+    pub fn syntax(msg: String, span: Span) -> Self {
+        NmError::new(ErrorKind::Syntax(msg), Some(span))
+    }
+
+    pub fn unbound(name: &str, span: Span) -> Self {
+        NmError::new(ErrorKind::Unbound(name.to_string()), Some(span))
+    }
+
+    pub fn type_mismatch(msg: String, span: Span) -> Self {
+        NmError::new(ErrorKind::TypeMismatch(msg), Some(span))
+    }
+
+    pub fn unsupported(msg: String, span: Span) -> Self {
+        NmError::new(ErrorKind::Unsupported(msg), Some(span))
+    }
+
+    pub fn arity(function: String, min: usize, max: Option<usize>, given: usize, span: Span) -> Self {
+        NmError::new(ErrorKind::Arity { function, min, max, given }, Some(span))
+    }
+
+    /// The calls that were active when the error happened, most recent first,
+    /// one per line in the style of a Lisp backtrace.
+    pub fn backtrace(&self) -> String {
+        self.trace.iter()
+            .enumerate()
+            .map(|(i, frame)| format!("  {}: {}\n", i, frame))
+            .collect()
+    }
+}
+
+impl fmt::Display for NmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(span) = self.span {
+            write!(f, " at {}", span)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Lex(err) => Some(err),
+            ErrorKind::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<LexError> for NmError {
+    fn from(err: LexError) -> Self {
+        let span = err.span;
+        NmError::new(ErrorKind::Lex(err), Some(span))
+    }
+}
+
+impl From<io::Error> for NmError {
+    fn from(err: io::Error) -> Self {
+        NmError::new(ErrorKind::Io(err), None)
+    }
+}
@@ -1,303 +1,426 @@
-use std::{collections::HashMap, fmt::Binary};
+use std::{collections::VecDeque, sync::Arc};
 
 use crate::
-    {program::{Item, Operator, self, BinaryOperator, Builtin, BinaryComparator, UnaryOperator, BooleanOperator}, 
-    list::List, 
-    parser::parse, 
-    lexer::lex,
-    builtins::builtinerate};
-
-fn f32_comparate(op: &BinaryComparator) -> Box<dyn Fn(f32, f32) -> bool> {
-    let result = match op {
-        BinaryComparator::Eq  => |a, b| f32::abs(a - b) < 0.000001,
-        BinaryComparator::Neq => |a, b| a != b,
+    {program::{Item, Operator, BinaryOperator, Builtin, BinaryComparator, UnaryOperator, BooleanOperator, Env, Bindings, Closure, RecGroup, Lambda, NativeFn},
+    list::List,
+    symbol::Symbol,
+    parser::parse,
+    resolver::resolve,
+    lexer::{lex, Span},
+    error::{NmError, ErrorKind, StackFrame},
+    builtins::builtinerate,
+    block};
+
+/// Floats are equal if they are within a millionth of each other, and `!=`
+/// is always the opposite of `==`.
+fn f32_eq(a: f32, b: f32) -> bool {
+    f32::abs(a - b) < 0.000001
+}
+
+pub(crate) fn f32_comparate(op: &BinaryComparator) -> fn(f32, f32) -> bool {
+    match op {
+        BinaryComparator::Eq  => f32_eq,
+        BinaryComparator::Neq => |a, b| !f32_eq(a, b),
         BinaryComparator::Gt  => |a, b| a > b,
         BinaryComparator::Gte => |a, b| a >= b,
         BinaryComparator::Lt  => |a, b| a < b,
         BinaryComparator::Lte => |a, b| a <= b,
-        _ => |a,b| false
-    };
-    Box::new(move |a, b| result(a,b))
+    }
 }
 
-fn i32_comparate(op: &BinaryComparator) -> Box<dyn Fn(i32, i32) -> bool> {
-    let result = match op {
+pub(crate) fn i64_comparate(op: &BinaryComparator) -> fn(i64, i64) -> bool {
+    match op {
         BinaryComparator::Eq  => |a, b| a == b,
         BinaryComparator::Neq => |a, b| a != b,
         BinaryComparator::Gt  => |a, b| a > b,
         BinaryComparator::Gte => |a, b| a >= b,
         BinaryComparator::Lt  => |a, b| a < b,
         BinaryComparator::Lte => |a, b| a <= b,
-        _ => |a,b| false
-    };
-    Box::new(move |a, b| result(a,b))
+    }
 }
 
-fn comparate(op: &BinaryComparator, args: List<Item>, env: &List<(&str, Item)>) -> Result<Item, String> {
-    let cdr = args.cdr();
-    let (arg1, arg2) = (args.car(), cdr.car());
+/// The fewest and most operands `op` takes. Arithmetic, comparisons, `and`
+/// and `or` take any number.
+pub fn operator_arity(op: &Operator) -> (usize, Option<usize>) {
+    match op {
+        Operator::UnaryOperator(_) | Operator::BooleanOperator(BooleanOperator::Not) => (1, Some(1)),
+        Operator::BinaryOperator(BinaryOperator::Add | BinaryOperator::Mul) => (0, None),
+        Operator::BinaryOperator(BinaryOperator::Sub | BinaryOperator::Div) => (1, None),
+        Operator::BinaryComparator(_) => (2, None),
+        Operator::BooleanOperator(BooleanOperator::And | BooleanOperator::Or) => (0, None),
+    }
+}
 
-    let arg1_eval = match arg1 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
-        }
-        None => return Err(format!("Missing argument for operator {:?}", op))
-    };
-    let arg2_eval = match arg2 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
+/// What an arithmetic operator gives with no operands, and what it combines a
+/// single operand with: `(- x)` is `(- 0 x)` and `(/ x)` is `(/ 1 x)`.
+pub(crate) fn identity(op: &BinaryOperator) -> i64 {
+    match op {
+        BinaryOperator::Add | BinaryOperator::Sub => 0,
+        BinaryOperator::Mul | BinaryOperator::Div => 1,
+    }
+}
+
+/// Applies `op` to already evaluated operands, failing with an arity error if
+/// there are too few or too many. Arithmetic works from left to right,
+/// comparisons hold if they hold for each adjacent pair of operands, and
+/// `(and)` is true while `(or)` is false.
+pub fn apply_operator(op: &Operator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    let (min, max) = operator_arity(op);
+    if operands.len() < min || max.is_some_and(|max| operands.len() > max) {
+        return Err(NmError::arity(op.to_string(), min, max, operands.len(), span));
+    }
+    match (op, operands) {
+        (Operator::BinaryOperator(binop), []) => Ok(Item::Number(identity(binop))),
+        (Operator::BinaryOperator(binop), [operand]) => apply(op, &[Item::Number(identity(binop)), operand.clone()], span),
+        (Operator::BinaryOperator(_), [first, rest @ ..]) => {
+            rest.iter().try_fold(first.clone(), |value, operand| apply(op, &[value, operand.clone()], span))
+        },
+        (Operator::BinaryComparator(_), _) => {
+            let and = Operator::BooleanOperator(BooleanOperator::And);
+            let mut pairs = operands.windows(2).map(|pair| apply(op, pair, span));
+            let first = pairs.next().expect("comparisons take at least two operands")?;
+            pairs.try_fold(first, |value, pair| apply(&and, &[value, pair?], span))
+        },
+        (Operator::BooleanOperator(boolop @ (BooleanOperator::And | BooleanOperator::Or)), _) => {
+            let start = Item::Boolean(*boolop == BooleanOperator::And);
+            operands.iter().try_fold(start, |value, operand| apply(op, &[value, operand.clone()], span))
+        },
+        _ => apply(op, operands, span)
+    }
+}
+
+/// Whether `value` settles the result of `op` if it is `and` or `or`, so that
+/// the operands after it are not evaluated: false for `and` and true for `or`.
+pub(crate) fn decides(op: &Operator, value: &Item) -> bool {
+    match (op, value) {
+        (Operator::BooleanOperator(BooleanOperator::And), Item::Boolean(value)) => !value,
+        (Operator::BooleanOperator(BooleanOperator::Or), Item::Boolean(value)) => *value,
+        _ => false
+    }
+}
+
+/// Applies `and` or `or`, evaluating its operands from left to right only
+/// until one settles the result.
+fn short_circuit(op: &Operator, operands: &List<Item>, env: &Env, span: Span) -> Result<Item, NmError> {
+    let mut value = Item::Boolean(*op == Operator::BooleanOperator(BooleanOperator::And));
+    for operand in operands.iter() {
+        if decides(op, &value) {
+            break;
         }
-        None => return Err(format!("Missing argument for operator {:?}", op))
-    };
+        value = apply(op, &[value, eval(operand, env)?], span)?;
+    }
+    Ok(value)
+}
+
+/// Applies `op` to one or two operands, sample by sample if any is a block.
+fn apply(op: &Operator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    if operands.iter().any(block::is_block) {
+        return block::apply_operator(op, operands, span);
+    }
+    match op {
+        Operator::BinaryOperator(binop) => operate(binop, operands, span),
+        Operator::BinaryComparator(bincomp) => comparate(bincomp, operands, span),
+        Operator::UnaryOperator(unop) => unarate(unop, operands, span),
+        Operator::BooleanOperator(boolop) => boolerate(boolop, operands, span),
+    }
+}
 
-    match (arg1_eval, arg2_eval) {
-        (Item::Number(num), Item::Number(num2)) => {
-            let i32_func = i32_comparate(&op);
-            Ok(Item::Boolean(i32_func(num, num2)))
+/// The operands of a binary operator that takes numbers. Integers stay
+/// integers unless the other operand is a float, in which case both are
+/// floats.
+enum Numbers {
+    Ints(i64, i64),
+    Floats(f32, f32),
+}
+
+fn promote(a: &Item, b: &Item, span: Span) -> Result<Numbers, NmError> {
+    match (a, b) {
+        (Item::Number(a), Item::Number(b)) => Ok(Numbers::Ints(*a, *b)),
+        (Item::Number(a), Item::Float(b)) => Ok(Numbers::Floats(*a as f32, *b)),
+        (Item::Float(a), Item::Number(b)) => Ok(Numbers::Floats(*a, *b as f32)),
+        (Item::Float(a), Item::Float(b)) => Ok(Numbers::Floats(*a, *b)),
+        (arg1, arg2) => Err(NmError::type_mismatch(format!("arguments {:?}, {:?} are not both numbers", arg1, arg2), span)),
+    }
+}
+
+fn comparate(op: &BinaryComparator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match promote(&operands[0], &operands[1], span)? {
+        Numbers::Ints(num, num2) => {
+            let i64_func = i64_comparate(op);
+            Ok(Item::Boolean(i64_func(num, num2)))
         },
-        (Item::Float(num), Item::Float(num2)) => {
-            let f32_func = f32_comparate(&op);
+        Numbers::Floats(num, num2) => {
+            let f32_func = f32_comparate(op);
             Ok(Item::Boolean(f32_func(num, num2)))
         },
-        _ => Err(format!("Error, arguments {:?}, {:?} are not the same type", arg1, arg2)),
     }
 }
 
-fn f32_matherate(op: &BinaryOperator) -> Box<dyn Fn(f32, f32) -> f32> {
-    let result = match op {
+pub(crate) fn f32_matherate(op: &BinaryOperator) -> fn(f32, f32) -> f32 {
+    match op {
         BinaryOperator::Mul => |a, b| a * b,
         BinaryOperator::Div => |a, b| a / b,
         BinaryOperator::Add => |a, b| a + b,
         BinaryOperator::Sub => |a, b| a - b,
-    };
-    Box::new(move |a, b| result(a,b))
+    }
 }
 
-fn i32_matherate(op: &BinaryOperator) -> Box<dyn Fn(i32, i32) -> i32> {
-    let result = match op {
-        BinaryOperator::Mul => |a, b| a * b,
-        BinaryOperator::Div => |a, b| a / b,
-        BinaryOperator::Add => |a, b| a + b,
-        BinaryOperator::Sub => |a, b| a - b,
-    };
-    Box::new(move |a, b| result(a,b))
+pub(crate) fn overflow(op: &BinaryOperator, num: i64, num2: i64, span: Span) -> NmError {
+    let expression = format!("({} {} {})", Operator::BinaryOperator(op.clone()), num, num2);
+    NmError::new(ErrorKind::Overflow(expression), Some(span))
 }
 
-fn operate(op: &BinaryOperator, args: List<Item>, env: &List<(&str, Item)>) -> Result<Item, String> {
-    let cdr = args.cdr();
-    let (arg1, arg2) = (args.car(), cdr.car());
-
-    let arg1_eval = match arg1 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
-        }
-        None => return Err(format!("Missing argument for operator {:?}", op))
-    };
-    let arg2_eval = match arg2 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
-        }
-        None => return Err(format!("Missing argument for operator {:?}", op))
-    };
+/// Integer arithmetic, giving `None` if the result would overflow or the
+/// divisor is zero.
+pub(crate) fn i64_matherate(op: &BinaryOperator) -> fn(i64, i64) -> Option<i64> {
+    match op {
+        BinaryOperator::Mul => i64::checked_mul,
+        BinaryOperator::Div => i64::checked_div,
+        BinaryOperator::Add => i64::checked_add,
+        BinaryOperator::Sub => i64::checked_sub,
+    }
+}
 
-    match (arg1_eval, arg2_eval) {
-        (Item::Number(num), Item::Number(num2)) => {
-            let i32_func = i32_matherate(op);
-            Ok(Item::Number(i32_func(num, num2)))
+fn operate(op: &BinaryOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match promote(&operands[0], &operands[1], span)? {
+        Numbers::Ints(_, 0) if *op == BinaryOperator::Div => {
+            Err(NmError::new(ErrorKind::DivisionByZero, Some(span)))
+        },
+        Numbers::Ints(num, num2) => {
+            let i64_func = i64_matherate(op);
+            i64_func(num, num2).map(Item::Number).ok_or_else(|| overflow(op, num, num2, span))
         },
-        (Item::Float(num), Item::Float(num2)) => {
+        Numbers::Floats(num, num2) => {
             let f32_func = f32_matherate(op);
             Ok(Item::Float(f32_func(num, num2)))
         },
-        _ => Err(format!("Error, arguments {:?}, {:?} are not the same type", arg1, arg2)),
     }
 }
 
-fn f32_unarate(op: &UnaryOperator) -> Box<dyn Fn(f32) -> f32> {
-    let result = match op {
+pub(crate) fn f32_unarate(op: &UnaryOperator) -> fn(f32) -> f32 {
+    match op {
         UnaryOperator::Exp => f32::exp,
         UnaryOperator::Log => f32::ln,
         UnaryOperator::Rec => |a| 1.0 / a,
         UnaryOperator::Sin => f32::sin,
-    };
-    Box::new(move |a| result(a))
+    }
 }
 
-fn i32_unarate(op: &UnaryOperator) -> Box<dyn Fn(i32) -> f32> {
-    let result = match op {
+pub(crate) fn i64_unarate(op: &UnaryOperator) -> fn(i64) -> f32 {
+    match op {
         UnaryOperator::Exp => |a| { f32::exp(a as f32) },
         UnaryOperator::Log => |a| { f32::ln(a as f32) },
         UnaryOperator::Rec => |a| 1.0 / a as f32,
         UnaryOperator::Sin => |a| { f32::sin( a as f32) },
-    };
-    Box::new(move |a| result(a))
+    }
 }
 
-fn unarate(op: &UnaryOperator, args: List<Item>, env: &List<(&str, Item)>) -> Result<Item, String> {
-    let arg1 = args.car();
-
-    let arg1_eval = match arg1 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
-        }
-        None => return Err(format!("Missing argument for operator {:?}", op))
-    };
-
-    match arg1_eval {
+fn unarate(op: &UnaryOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    match operands[0] {
         Item::Number(num) => {
-            let i32_func = i32_unarate(op);
-            Ok(Item::Float(i32_func(num)))
+            let i64_func = i64_unarate(op);
+            Ok(Item::Float(i64_func(num)))
         },
         Item::Float(num) => {
             let f32_func = f32_unarate(op);
             Ok(Item::Float(f32_func(num)))
         },
-        _ => Err(format!("Error, argument {:?}, not suitable for {:?}", arg1_eval, op))
+        ref arg => Err(NmError::type_mismatch(format!("argument {:?} not suitable for {}", arg, Operator::UnaryOperator(op.clone())), span))
     }
 }
 
-fn boolerate(op: &BooleanOperator, args: List<Item>, env: &List<(&str, Item)>) -> Result<Item, String> {
-    let cdr = args.cdr();
-    let (arg1, arg2) = (args.car(), cdr.car());
+fn boolerate(op: &BooleanOperator, operands: &[Item], span: Span) -> Result<Item, NmError> {
+    let operator = Operator::BooleanOperator(op.clone());
+    let bools = operands.iter().map(|operand| match operand {
+        Item::Boolean(b) => Ok(*b),
+        _ => Err(NmError::type_mismatch(format!("argument {:?} not suitable for {}", operand, operator), span))
+    }).collect::<Result<Vec<bool>, NmError>>()?;
+    match op {
+        BooleanOperator::Not => Ok(Item::Boolean(!bools[0])),
+        BooleanOperator::And => Ok(Item::Boolean(bools[0] && bools[1])),
+        BooleanOperator::Or => Ok(Item::Boolean(bools[0] || bools[1])),
+    }
+}
 
-    let arg1_eval = match arg1 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => evaluated,
-            Err(msg) => return Err(msg)
-        }
-        None => return Err(format!("Missing argument for operator {:?}", op))
+pub fn default_env() -> Env {
+    let env = Env::default();
+    env.define(Symbol::intern("e"), Item::Float(std::f32::consts::E));
+    env.define(Symbol::intern("pi"), Item::Float(std::f32::consts::PI));
+    env
+}
+
+/// Adds a frame holding every function of `group` to `env`, each closed over
+/// the same group.
+pub fn bind_group(env: &Bindings, group: &Arc<RecGroup>) -> Bindings {
+    let frame = group.members.iter()
+        .filter_map(|lambda| lambda.name.map(|name| (name, Item::Function(Closure { lambda: lambda.clone(), group: Some(group.clone()) }))))
+        .collect();
+    env.prepend(frame)
+}
+
+/// Binds `args` to the parameters of `lambda` in a new frame on top of `env`.
+/// Defaults of optional parameters are evaluated after the parameters before
+/// them are bound.
+fn bind_args(lambda: &Lambda, function: &str, args: &[Item], span: Span, env: Env) -> Result<Env, NmError> {
+    let params = &lambda.params;
+    if args.len() < params.min() || params.max().is_some_and(|max| args.len() > max) {
+        return Err(NmError::arity(function.to_string(), params.min(), params.max(), args.len(), span));
+    }
+
+    let mut args = args.iter();
+    let mut frame = params.required.iter().zip(&mut args).map(|(name, arg)| (*name, arg.clone())).collect::<Vec<_>>();
+    for (name, default) in params.optional.iter() {
+        let value = match args.next() {
+            Some(arg) => arg.clone(),
+            None => eval(default, &env.push_frame(frame.clone()))?
+        };
+        frame.push((*name, value));
+    }
+    if let Some(rest) = &params.rest {
+        let extra = args.cloned().collect::<Vec<Item>>();
+        let list = extra.into_iter().rev().fold(List::new(), |list, item| list.prepend(item));
+        frame.push((*rest, Item::ListLiteral(list)));
+    }
+    Ok(env.push_frame(frame))
+}
+
+/// The environment the body of `closure` runs in when called as described by
+/// `frame`: its captured bindings, its group if it has one, and its arguments.
+pub(crate) fn enter(closure: &Closure, frame: &StackFrame, env: &Env) -> Result<Env, NmError> {
+    let lambda = &closure.lambda;
+    let captured = match &closure.group {
+        Some(group) => bind_group(&lambda.env, group),
+        None => lambda.env.clone()
     };
+    bind_args(lambda, &frame.function, &frame.args, frame.span, env.with_locals(captured))
+}
 
-    let arg2_eval = match arg2 {
-        Some(item) => match eval(item, env) {
-            Ok(evaluated) => Some(evaluated),
-            Err(msg) => return Err(msg)
-        }
-        None => None
+/// Calls a host function after checking its arity. Errors it returns without
+/// a location are given the location of the call.
+pub(crate) fn call_native(native: &NativeFn, args: Vec<Item>, span: Span) -> Result<Item, NmError> {
+    let result = if args.len() < native.min || native.max.is_some_and(|max| args.len() > max) {
+        Err(NmError::arity(native.name.clone(), native.min, native.max, args.len(), span))
+    }
+    else {
+        (native.func)(&args)
     };
+    result.map_err(|mut err| {
+        err.span.get_or_insert(span);
+        err.trace.push(StackFrame { function: native.name.clone(), args, span });
+        err
+    })
+}
 
-    match arg1_eval {
-        Item::Boolean(bool) => {
-            match op {
-                BooleanOperator::Not => Ok(Item::Boolean(!bool)),
-                BooleanOperator::And => {
-                    match arg2_eval {
-                        Some(Item::Boolean(other)) => Ok(Item::Boolean(bool && other)),
-                        _ => Err(format!("Error in boolean and : {:?} is not a boolean", arg2_eval)),
-                        None => Err(format!("Error, missing second arg to boolean and"))
-                    }
-                },
-                BooleanOperator::Or => {
-                    match arg2_eval {
-                        Some(Item::Boolean(other)) => Ok(Item::Boolean(bool || other)),
-                        _ => Err(format!("Error in boolean or : {:?} is not a boolean", arg2_eval)),
-                        None => Err(format!("Error, missing second arg to boolean or"))
-                    }
+/// How many calls made in tail position are remembered for backtraces. Tail
+/// calls do not grow the Rust stack, so older ones are forgotten.
+const TAIL_FRAMES: usize = 32;
+
+/// What is left to do after one step of evaluation. Expressions in tail
+/// position are handed back instead of being evaluated, so `eval` can loop
+/// rather than recurse.
+pub enum Step {
+    Done(Item),
+    Tail(Item, Env),
+    /// A function call: the frame to report if it fails, its body and the
+    /// environment with its arguments bound.
+    Call(StackFrame, Item, Env),
+}
+
+pub fn eval(program: &Item, env: &Env) -> Result<Item, NmError> {
+    let mut frames = VecDeque::new();
+    let mut step = eval_step(program, env);
+    loop {
+        match step {
+            Ok(Step::Done(item)) => return Ok(item),
+            Ok(Step::Tail(next, next_env)) => step = eval_step(&next, &next_env),
+            Ok(Step::Call(frame, body, call_env)) => {
+                if frames.len() == TAIL_FRAMES {
+                    frames.pop_front();
                 }
-                _ => Err(format!("Boolean operator {:?} not implemented yet", op))
+                frames.push_back(frame);
+                step = eval_step(&body, &call_env);
+            },
+            Err(mut err) => {
+                err.trace.extend(frames.into_iter().rev());
+                return Err(err);
             }
-        },
-        _ => Err(format!("Error, argument {:?}, not suitable for {:?}", arg1_eval, op))
+        }
     }
 }
 
-pub fn default_env() -> List<(&'static str, Item)> {
-    let mut list = List::new();
-    list = list.prepend(("e", Item::Float(std::f32::consts::E)));
-    list = list.prepend(("pi", Item::Float(std::f32::consts::PI)));
-    list
-}
-
-pub fn eval(program: &Item, env: &List<(&str, Item)>) -> Result<Item, String> {
+fn eval_step(program: &Item, env: &Env) -> Result<Step, NmError> {
     match program {
-        Item::List(list) => {
+        Item::List(list, span) => {
             //evalute the first arg incase it's a function or something
-            let first_arg;
-            match list.car() {
-                Some(item) => first_arg = item,
-                None => return Ok(Item::Nil)
+            let first_arg = match list.car() {
+                Some(item) => item,
+                None => return Ok(Step::Done(Item::Nil))
             };
-            let first_arg_eval;
-            match eval(&first_arg, env) {
-                Ok(res) => first_arg_eval = res,
-                Err(msg) => return Err(msg)
+            let first_arg_eval = eval(first_arg, env)?;
+
+            // written out, `and` and `or` short-circuit, as they do when compiled
+            if let Item::Operator(op @ Operator::BooleanOperator(BooleanOperator::And | BooleanOperator::Or)) = first_arg {
+                short_circuit(op, &list.cdr(), env, *span).map(Step::Done)
+            }
+            else if let Item::Operator(op) = first_arg_eval {
+                let operands = list.cdr().iter().map(|arg| eval(arg, env)).collect::<Result<Vec<Item>, NmError>>()?;
+                apply_operator(&op, &operands, *span).map(Step::Done)
             }
+            else if let Item::Function(closure) = first_arg_eval {
+                let args = list.cdr().iter().map(|arg| eval(arg, env)).collect::<Result<Vec<Item>, NmError>>()?;
+                let lambda = &closure.lambda;
+                let function = match (&lambda.name, first_arg) {
+                    (Some(name), _) => name.to_string(),
+                    (None, Item::Identifier(name, _)) => name.to_string(),
+                    _ => String::from("lambda")
+                };
 
-            if let Item::Operator(op) = first_arg_eval {
-                match op {
-                    Operator::BinaryOperator(binop) => operate(&binop, list.cdr(), env),
-                    Operator::BinaryComparator(bincomp) => comparate(&bincomp, list.cdr(), env),
-                    Operator::UnaryOperator(unop) => unarate(&unop, list.cdr(), env),
-                    Operator::BooleanOperator(boolop) => boolerate(&boolop, list.cdr(), env),
-                    _ => Err(format!("Operator {:?} not implemented yet!", op))
+                let frame = StackFrame { function, args, span: *span };
+                match enter(&closure, &frame, env) {
+                    Ok(call_env) => Ok(Step::Call(frame, lambda.body.clone(), call_env)),
+                    Err(mut err) => {
+                        err.trace.push(frame);
+                        Err(err)
+                    }
                 }
             }
-            else if let Item::Function(arg_names, func) = first_arg_eval {
-                let args = list.cdr();
-                let l = Item::Builtin(Builtin::Let);
-
-                let mut new_program_list = List::new();
-                new_program_list = new_program_list.prepend(*func);
-
-                let mut names_it = arg_names.iter();
-                let mut arg_it = args.iter();
-                while let (Some(name), Some(arg)) = (names_it.next(), arg_it.next()) {
-                    let mut arg_list = List::new();
-                    arg_list = arg_list.prepend(arg.clone());
-                    arg_list = arg_list.prepend(name.clone());
-
-                    let appender = Item::List(arg_list);
-                    new_program_list = new_program_list.prepend(appender);
-                }
-                new_program_list = new_program_list.prepend(Item::Builtin(Builtin::Let));
-
-                let new_program = Item::List(new_program_list);
-                eval(&new_program, env)
+            else if let Item::NativeFn(native) = first_arg_eval {
+                let args = list.cdr().iter().map(|arg| eval(arg, env)).collect::<Result<Vec<Item>, NmError>>()?;
+                call_native(&native, args, *span).map(Step::Done)
             }
             else if let Some(Item::Builtin(s)) = list.car() {
-                builtinerate(s, &list.cdr(), env)
+                builtinerate(s, &list.cdr(), *span, env)
             }
             else {
-                let mut evaluated_vec: Vec<Result<Item, String>> = list.iter().map(|item| eval(item, env)).collect();
-                evaluated_vec.reverse();
-
+                let evaluated = list.iter().map(|item| eval(item, env)).collect::<Result<Vec<Item>, NmError>>()?;
                 let mut new_list = List::new();
-
-                for evaled in evaluated_vec {
-                    match evaled {
-                       Ok(item) => {
-                            new_list = new_list.prepend(item);
-                        },
-                        Err(msg) => return Err(msg)
-                    }
+                for item in evaluated.into_iter().rev() {
+                    new_list = new_list.prepend(item);
                 }
-                Ok(Item::ListLiteral(new_list))
-                //Ok(program.clone())
-                //Err(format!("found something other than op or func at front of list"))
+                Ok(Step::Done(Item::ListLiteral(new_list)))
             }
         },
-        Item::Identifier(ident) => {
-            let mut cursor = env.iter();
-            while let Some((string, item)) = cursor.next() {
-                if string == ident {
-                    return Ok(item.clone())
-                }
-            }
-            return Err(format!("Identifier not found: {}", ident))
+        Item::Identifier(ident, span) => {
+            env.lookup(*ident)
+                .map(Step::Done)
+                .ok_or_else(|| NmError::unbound(ident.as_str(), *span))
+        },
+        Item::Local(ident, address, span) => {
+            env.local(*address)
+                .map(Step::Done)
+                .ok_or_else(|| NmError::unbound(ident.as_str(), *span))
         },
-        _ => Ok(program.clone())
+        Item::Global(ident, slot, span) => {
+            env.global(*slot)
+                .map(Step::Done)
+                .ok_or_else(|| NmError::unbound(ident.as_str(), *span))
+        },
+        _ => Ok(Step::Done(program.clone()))
     }
 }
 
-pub fn eval_string(program_string : &String, env: List<(&str, Item)>) -> Result<Item, String> {
-    let tokens = lex(program_string);
-    match parse(tokens) {
-        Ok(prog) => eval(&prog, &env),
-        Err(msg) => Err(msg)
-    }
-}
\ No newline at end of file
+pub fn eval_string(program_string: &str, env: Env) -> Result<Item, NmError> {
+    let tokens = lex(program_string)?;
+    let prog = resolve(&parse(tokens)?, &env)?;
+    eval(&prog, &env)
+}
@@ -0,0 +1,89 @@
+use std::{fs, path::Path, sync::Arc};
+
+use crate::{program::{Item, Env, NativeFn}, resolver, optimizer, signal::CompiledSignal, block, eval::{eval, eval_string, default_env}, error::NmError, symbol::Symbol};
+
+/// An nm session. Definitions made by one call are kept for the next, so a
+/// host can load a patch once and then evaluate against it as often as it
+/// likes. An `Interpreter` can be shared between threads.
+pub struct Interpreter {
+    env: Env,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter { env: default_env() }
+    }
+
+    pub fn eval_str(&self, source: &str) -> Result<Item, NmError> {
+        eval_string(source, self.env.clone())
+    }
+
+    /// Resolves a parsed program once so it can be passed to `eval_with`
+    /// many times with values for `locals`, given in the same order.
+    /// Unbound names are reported here rather than when it runs.
+    pub fn resolve(&self, program: &Item, locals: &[&str]) -> Result<Item, NmError> {
+        let frame = locals.iter().map(|name| (Symbol::intern(name), Item::Nil)).collect();
+        resolver::resolve(program, &self.env.push_frame(frame))
+    }
+
+    /// Simplifies a parsed program ahead of time against the current globals,
+    /// treating `locals` as unknown. The result still needs resolving.
+    pub fn optimize(&self, program: &Item, locals: &[&str]) -> Item {
+        let frame = locals.iter().map(|name| (Symbol::intern(name), Item::Nil)).collect();
+        optimizer::optimize(program, &self.env.push_frame(frame))
+    }
+
+    /// Evaluates an already parsed program with `locals` bound on top of the
+    /// globals, without keeping the locals afterwards.
+    pub fn eval_with(&self, program: &Item, locals: &[(&str, Item)]) -> Result<Item, NmError> {
+        let frame = locals.iter().map(|(name, value)| (Symbol::intern(name), value.clone())).collect();
+        eval(program, &self.env.push_frame(frame))
+    }
+
+    /// Evaluates an already resolved program for a block of samples, with each
+    /// of `inputs` bound to its samples, writing one result per sample to
+    /// `out`.
+    pub fn eval_block(&self, program: &Item, inputs: &[(&str, &[f32])], out: &mut [f32]) -> Result<(), NmError> {
+        block::eval_block(program, inputs, &self.env, out)
+    }
+
+    /// Compiles a parsed program into a signal of `inputs` against the current
+    /// globals, for hosts that evaluate it once per sample.
+    pub fn compile_signal(&self, program: &Item, inputs: &[&str]) -> Result<CompiledSignal, NmError> {
+        CompiledSignal::compile(program, inputs, &self.env)
+    }
+
+    pub fn load_file<P: AsRef<Path>>(&self, path: P) -> Result<Item, NmError> {
+        let source = fs::read_to_string(path)?;
+        self.eval_str(&source)
+    }
+
+    /// Makes `value` available to nm code as the global `name`.
+    pub fn define_native(&self, name: &str, value: Item) {
+        self.env.define(Symbol::intern(name), value);
+    }
+
+    /// Exposes a Rust function to nm code as `name`, taking exactly `arity`
+    /// arguments. Use [`NativeFn::new`] with `define_native` for functions
+    /// with optional or variable arguments.
+    pub fn register_fn<F>(&self, name: &str, arity: usize, func: F)
+    where F: Fn(&[Item]) -> Result<Item, NmError> + Send + Sync + 'static {
+        self.define_native(name, Item::NativeFn(Arc::new(NativeFn::new(name, arity, Some(arity), func))));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Item> {
+        self.env.globals.read().unwrap().lookup(Symbol::intern(name)).cloned()
+    }
+
+    /// The environment globals live in, for hosts that want to call `eval`
+    /// themselves.
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+}
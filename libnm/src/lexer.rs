@@ -1,58 +1,253 @@
+use std::fmt;
+
 use regex::Regex;
 
-pub fn lex(file_text : &String) -> Vec<String> {
+/// Where a token starts in the source text. `offset` is a byte offset, `line`
+/// and `column` are 1-based and count characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Span { offset: 0, line: 1, column: 1 }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    OpenParen,
+    CloseParen,
+    String,
+    Identifier,
+    Float,
+    Number,
+    /// Text that is not a token, skipped by [`lex_recovering`].
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub span: Span,
+    /// What is wrong with the text, for `Error` tokens.
+    pub error: Option<LexError>,
+}
+
+/// A character that doesn't start any token, and where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LexError {
+    pub character: char,
+    pub span: Span,
+    /// The number `character` came straight after without a delimiter, in
+    /// which case `span` is where the number starts.
+    pub number: Option<String>,
+}
+
+impl LexError {
+    /// What went wrong, without the location.
+    pub fn description(&self) -> String {
+        match &self.number {
+            Some(number) => format!("Unexpected character {:?} after number {}", self.character, number),
+            None => format!("Unexpected character {:?}", self.character)
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.description(), self.span)
+    }
+}
+
+/// Whether `c` can end a token: whitespace, a paren, a string or a comment.
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';')
+}
+
+impl std::error::Error for LexError {}
+
+/// Moves `span` forward over `text`, keeping line and column up to date.
+fn advance(span: &mut Span, text: &str) {
+    for c in text.chars() {
+        if c == '\n' {
+            span.line += 1;
+            span.column = 1;
+        }
+        else {
+            span.column += 1;
+        }
+    }
+    span.offset += text.len();
+}
+
+/// Length of the (possibly nested) `#| ... |#` comment at the start of `s`, or
+/// `None` if it is never closed.
+fn block_comment_len(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with("#|") {
+            depth += 1;
+            i += 2;
+        }
+        else if s[i..].starts_with("|#") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(i);
+            }
+        }
+        else {
+            i += s[i..].chars().next().map_or(1, char::len_utf8);
+        }
+    }
+    None
+}
+
+/// Adds an `Error` token for `text`, which is not a token because of
+/// `character`, and moves `span` past it.
+fn skip(tokens: &mut Vec<Token>, span: &mut Span, text: &str, character: char, number: Option<String>) {
+    let error = LexError { character, span: *span, number };
+    tokens.push(Token { kind: TokenKind::Error, text: text.to_string(), span: *span, error: Some(error) });
+    advance(span, text);
+}
+
+/// Index just past the expression starting at `tokens[i]`.
+fn skip_datum(tokens: &[Token], i: usize) -> usize {
+    match tokens.get(i).map(|token| token.kind) {
+        Some(TokenKind::OpenParen) => {
+            let mut depth = 0;
+            for (j, token) in tokens.iter().enumerate().skip(i) {
+                match token.kind {
+                    TokenKind::OpenParen => depth += 1,
+                    TokenKind::CloseParen => depth -= 1,
+                    _ => ()
+                }
+                if depth == 0 {
+                    return j + 1;
+                }
+            }
+            tokens.len()
+        },
+        Some(TokenKind::CloseParen) | None => i,
+        Some(_) => i + 1,
+    }
+}
+
+/// Drops the expression following each `#;`. `datum_comments` holds, for every
+/// `#;` seen, the index of the token that came right after it. `Error` tokens
+/// are kept, as text that is not a token is a problem even in a comment.
+fn strip_datum_comments(tokens: Vec<Token>, datum_comments: &[usize]) -> Vec<Token> {
+    let mut kept = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let mut skips = datum_comments.iter().filter(|index| **index == i).count();
+        if skips == 0 {
+            kept.push(tokens[i].clone());
+            i += 1;
+            continue;
+        }
+        while skips > 0 && i < tokens.len() {
+            let next = skip_datum(&tokens, i);
+            if next == i {
+                break;
+            }
+            kept.extend(tokens[i..next].iter().filter(|token| token.kind == TokenKind::Error).cloned());
+            i = next;
+            skips -= 1;
+        }
+        if skips > 0 && i < tokens.len() {
+            kept.push(tokens[i].clone());
+            i += 1;
+        }
+    }
+    kept
+}
+
+pub fn lex(file_text: &str) -> Result<Vec<Token>, LexError> {
+    let tokens = lex_recovering(file_text);
+    match tokens.iter().find_map(|token| token.error.clone()) {
+        Some(err) => Err(err),
+        None => Ok(tokens)
+    }
+}
+
+/// Like [`lex`], but instead of stopping at text that is not a token it emits
+/// an `Error` token for it and carries on, so that the parser can report
+/// every problem in the file. A bad character is skipped on its own, a
+/// malformed number up to the next delimiter, and a comment or string that is
+/// never closed runs to the end of the text.
+pub fn lex_recovering(file_text: &str) -> Vec<Token> {
     let open_paren = Regex::new(r"^(')?\(").unwrap();
     let close_paren = Regex::new(r"^\)").unwrap();
-    let string = Regex::new(r#"^"[^"]*""#).unwrap();
-    let ident = Regex::new(r"^[a-z][a-z0-9_-]*").unwrap();
-    let float = Regex::new(r"^[0-9]+\.[0-9]+").unwrap();
-    let num = Regex::new(r"^[0-9]+").unwrap();
-    let op = Regex::new(r"^(>|<|=|!|\^|/|\*|\+|-)+").unwrap();
-	let end = Regex::new(r"^\s+$").unwrap();
-
-    let starts_with_white = Regex::new(r"^\s+.").unwrap();
-
-    let list = vec![
-        (open_paren, "open paren"),
-        (close_paren, "close paren"),
-        (op, "op"),
-        (string, "string"),
-        (ident, "ident"),
-        (float, "float"),
-        (num, "num"),
+    let string = Regex::new(r#"^"([^"\\]|\\(?s:.))*""#).unwrap();
+    let ident = Regex::new(r"^[A-Za-z!$%&*/:<=>?^_~+\-.@][A-Za-z0-9!$%&*/:<=>?^_~+\-.@]*").unwrap();
+    let float = Regex::new(r"^[+-]?([0-9]+\.[0-9]*([eE][+-]?[0-9]+)?|\.[0-9]+([eE][+-]?[0-9]+)?|[0-9]+[eE][+-]?[0-9]+)").unwrap();
+    let num = Regex::new(r"^[+-]?[0-9]+").unwrap();
+    let white = Regex::new(r"^\s+").unwrap();
+    let line_comment = Regex::new(r"^;[^\n]*").unwrap();
+
+    let list = [
+        (open_paren, TokenKind::OpenParen),
+        (close_paren, TokenKind::CloseParen),
+        (float, TokenKind::Float),
+        (num, TokenKind::Number),
+        (string, TokenKind::String),
+        (ident, TokenKind::Identifier),
     ];
 
-    let mut s = file_text.to_string();
-    let mut tokens : Vec<String> = Vec::new();
-    while s.len() > 0 {
-        let mut found_match = false;
-        if starts_with_white.is_match(&s) {
-            s = s.split_at(1).1.to_string();
+    let mut span = Span::default();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut datum_comments = Vec::new();
+    while span.offset < file_text.len() {
+        let s = &file_text[span.offset..];
+        if let Some(found) = white.find(s).or_else(|| line_comment.find(s)) {
+            advance(&mut span, found.as_str());
             continue;
         }
-		if end.is_match(&s) {
-			break;
-		}
-        for reg in list.iter() {
-            if reg.0.is_match(&s) {
-                let mut finds = reg.0.find_iter(&s).map(|x| x.as_str());
-                let find = finds.next();
-                match find {
-                    Some(token) => {
-						let mut token_string = token.to_string();
-                        tokens.push(token.to_string());
-                        s = s.split_at(token.len()).1.to_string();
-                    },
-                    _ => println!("What")
-                }
-                //println!("{}", s);
-                found_match = true;
-                break;
+        if s.starts_with("#|") {
+            match block_comment_len(s) {
+                Some(len) => advance(&mut span, &s[..len]),
+                None => skip(&mut tokens, &mut span, s, '#', None)
             }
+            continue;
+        }
+        if s.starts_with("#;") {
+            datum_comments.push(tokens.len());
+            advance(&mut span, "#;");
+            continue;
         }
-        if !found_match {
-            println!("ERROR: {:?},\n{:?}...", tokens, s.split_at(10).0);
+        match list.iter().find_map(|(reg, kind)| reg.find(s).map(|found| (found.as_str(), *kind))) {
+            Some((text, kind)) => {
+                // a number runs up to a delimiter, so `1x` is not `1` then `x`
+                if let (TokenKind::Float | TokenKind::Number, Some(next)) = (kind, s[text.len()..].chars().next()) {
+                    if !is_delimiter(next) {
+                        let end = s.find(is_delimiter).unwrap_or(s.len());
+                        skip(&mut tokens, &mut span, &s[..end], next, Some(text.to_string()));
+                        continue;
+                    }
+                }
+                tokens.push(Token { kind, text: text.to_string(), span, error: None });
+                advance(&mut span, text);
+            },
+            None => {
+                let character = s.chars().next().unwrap_or_default();
+                // a string that is never closed would otherwise be read as code
+                let len = if character == '"' { s.len() } else { character.len_utf8() };
+                skip(&mut tokens, &mut span, &s[..len], character, None);
+            }
         }
     }
-	tokens
-}
\ No newline at end of file
+    strip_datum_comments(tokens, &datum_comments)
+}
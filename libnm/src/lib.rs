@@ -2,23 +2,57 @@
 
 pub mod lexer;
 pub mod list;
+pub mod symbol;
 pub mod program;
 pub mod parser;
+pub mod resolver;
 pub mod eval;
+pub mod compiler;
+pub mod vm;
+pub mod signal;
+pub mod block;
+pub mod optimizer;
 pub mod builtins;
+pub mod error;
+pub mod interpreter;
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, f32::consts::{E, PI}, default};
+    use std::{collections::HashMap, f32::consts::{E, PI}, default, sync::{Arc, RwLock}};
 
-    use crate::{eval::{default_env, eval_string}, program::Item};
+    use crate::{eval::default_env, program::{Item, Env}, error::NmError};
 
     use super::*;
 
+    /// Runs `program` optimized, on the bytecode VM and on the tree-walking
+    /// evaluator, checks that they agree and returns what the evaluator gave.
+    /// The first two run on copies of `env`'s globals, so each run starts
+    /// from the same definitions and only the evaluator's are left behind.
+    /// Anything a program prints is still printed three times.
+    fn eval_string(program: &str, env: Env) -> Result<Item, NmError> {
+        let copy = || Env { locals: env.locals.clone(), globals: Arc::new(RwLock::new(env.globals.read().unwrap().clone())) };
+        let optimized_env = copy();
+        let optimized = parser::parse_string(program.to_string())
+            .and_then(|parsed| resolver::resolve(&optimizer::optimize(&parsed, &optimized_env), &optimized_env))
+            .and_then(|resolved| eval::eval(&resolved, &optimized_env));
+        let compiled = vm::run_string(program, copy());
+        let reference = eval::eval_string(program, env);
+        for (name, got) in [("vm", &compiled), ("optimizer", &optimized)] {
+            match (&reference, got) {
+                (Ok(expected), Ok(got)) => assert_eq!(format!("{:?}", expected), format!("{:?}", got), "{} disagrees on {}", name, program),
+                (Err(expected), Err(got)) => assert_eq!(expected.to_string(), got.to_string(), "{} disagrees on {}", name, program),
+                _ => panic!("{} disagrees on {}: expected {:?}, got {:?}", name, program, reference, got)
+            }
+        }
+        reference
+    }
+
     #[test]
     fn lexer() {
         use lexer::lex;
 
+        let lex = |s: &String| lex(s).unwrap().into_iter().map(|token| token.text).collect::<Vec<String>>();
+
         assert_eq!(lex(&String::from("(sin (a b c) c d e (1 2 3) abcdefg)")), 
             vec![ "(", "sin", "(", "a", "b", "c", ")", 
                 "c", "d", "e", "(", "1", "2", "3", 
@@ -40,6 +74,316 @@ mod tests {
             .iter().map(|s| String::from(*s)).collect::<Vec<String>>());
     }
 
+    #[test]
+    fn token_spans() {
+        use lexer::{lex, Span, TokenKind};
+
+        let tokens = lex("(let (x 4)\n  (* x 2.5))").unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::OpenParen);
+        assert_eq!(tokens[1].kind, TokenKind::Identifier);
+        assert_eq!(tokens[1].span, Span { offset: 1, line: 1, column: 2 });
+        assert_eq!(tokens[3].text, "x");
+        assert_eq!(tokens[3].span, Span { offset: 6, line: 1, column: 7 });
+
+        let star = &tokens[7];
+        assert_eq!((star.kind, star.text.as_str()), (TokenKind::Identifier, "*"));
+        assert_eq!(star.span, Span { offset: 14, line: 2, column: 4 });
+        assert_eq!(tokens[9].kind, TokenKind::Float);
+        assert_eq!(tokens[9].span, Span { offset: 18, line: 2, column: 8 });
+    }
+
+    #[test]
+    fn lex_errors() {
+        use lexer::{lex, LexError, Span};
+
+        assert_eq!(lex("(+ 1, 2)"), Err(LexError { character: ',', span: Span { offset: 3, line: 1, column: 4 }, number: Some(String::from("1")) }));
+        assert_eq!(lex("(print \"unterminated)").map_err(|err| err.character), Err('"'));
+        assert_eq!(lex("1 2 ["), Err(LexError { character: '[', span: Span { offset: 4, line: 1, column: 5 }, number: None }));
+        assert_eq!(lex("(+ 1 x)").map(|tokens| tokens.len()), Ok(5));
+
+        let from_eval = eval_string(&String::from("(list\n  [1 2])"), default_env());
+        assert_eq!(from_eval.map_err(|err| err.to_string()).err(), Some(String::from("Unexpected character '[' at 2:3")));
+        assert!(parser::parse_string(String::from("(* 2 ,3)")).is_err());
+    }
+
+    #[test]
+    fn comments() {
+        use lexer::lex;
+
+        let texts = |s: &str| lex(s).unwrap().into_iter().map(|token| token.text).collect::<Vec<String>>();
+
+        assert_eq!(texts("(+ 1 2) ; add them\n; a whole line\n3"), vec!["(", "+", "1", "2", ")", "3"]);
+        assert_eq!(texts("(+ 1 #| two |# 2)"), vec!["(", "+", "1", "2", ")"]);
+        assert_eq!(texts("#| outer #| inner |# still outer |# 4"), vec!["4"]);
+        assert_eq!(texts("(+ 1 #;(* 2 (+ 3 4)) 5)"), vec!["(", "+", "1", "5", ")"]);
+        assert_eq!(texts("#; #; 1 2 3"), vec!["3"]);
+        assert_eq!(texts("(a #;)"), vec!["(", "a", ")"]);
+        assert!(lex("(+ 1 #| never closed 2)").is_err());
+
+        let commented = "; doubles x\n(let (x 4) #| the value |# (* x #;ignored 2))";
+        match eval_string(&String::from(commented), default_env()) {
+            Ok(Item::Number(8)) => (),
+            other => panic!("{:?}", other)
+        }
+
+        // a file with nothing but comments in it does nothing
+        for empty in ["", "; just a note\n", "#| nothing |# #;(print 1)", "(progn)"] {
+            assert!(matches!(eval_string(empty, default_env()), Ok(Item::Nil)), "{:?}", empty);
+        }
+    }
+
+    #[test]
+    fn string_escapes() {
+        use parser::parse_string;
+
+        let parse_str = |s: &str| match parse_string(String::from(s)) {
+            Ok(Item::List(list, _)) => match list.cdr().car() {
+                Some(Item::String(string)) => Ok(string.clone()),
+                other => panic!("{:?}", other)
+            },
+            Ok(other) => panic!("{:?}", other),
+            Err(err) => Err(err.to_string())
+        };
+
+        assert_eq!(parse_str(r#""say \"hi\"""#), Ok(String::from("say \"hi\"")));
+        assert_eq!(parse_str(r#""a\\b\tc\nd""#), Ok(String::from("a\\b\tc\nd")));
+        assert_eq!(parse_str(r#""\u{48}\u{e9}\u{1F3B5}""#), Ok(String::from("H\u{e9}\u{1F3B5}")));
+        assert_eq!(parse_str("\"two\nlines\""), Ok(String::from("two\nlines")));
+        assert!(parse_str(r#""bad \q escape""#).is_err());
+        assert!(parse_str(r#""bad \u{110000} code""#).is_err());
+        assert!(parse_str(r#""bad \u{41 brace""#).is_err());
+
+        let printed = format!("{:?}", Item::String(String::from("tab\tquote\"back\\\u{7}")));
+        assert_eq!(printed, r#""tab\tquote\"back\\\u{7}""#);
+        assert_eq!(parse_str(&printed), Ok(String::from("tab\tquote\"back\\\u{7}")));
+    }
+
+    #[test]
+    fn numeric_literals() {
+        use lexer::{lex, TokenKind};
+
+        let kinds = |s: &str| lex(s).unwrap().into_iter().map(|token| (token.kind, token.text)).collect::<Vec<_>>();
+        assert_eq!(kinds("-3 +4 1e-3 .5 -.25 2.5E2 7."), vec![
+            (TokenKind::Number, String::from("-3")),
+            (TokenKind::Number, String::from("+4")),
+            (TokenKind::Float, String::from("1e-3")),
+            (TokenKind::Float, String::from(".5")),
+            (TokenKind::Float, String::from("-.25")),
+            (TokenKind::Float, String::from("2.5E2")),
+            (TokenKind::Float, String::from("7.")),
+        ]);
+        assert_eq!(kinds("(- 3 2)")[1], (TokenKind::Identifier, String::from("-")));
+        assert_eq!(kinds("(+ 1(- 2.5)3)").len(), 9);
+
+        // a number must be followed by a delimiter
+        let error = |s: &str| lex(s).unwrap_err().to_string();
+        assert_eq!(error("(+ 1x 2)"), "Unexpected character 'x' after number 1 at 1:4");
+        assert_eq!(error("(* 2 1.5.2)"), "Unexpected character '.' after number 1.5 at 1:6");
+        assert_eq!(error("1e"), "Unexpected character 'e' after number 1 at 1:1");
+        let from_eval = eval_string(&String::from("(+ 1x 2)"), default_env()).unwrap_err().to_string();
+        assert_eq!(from_eval, "Unexpected character 'x' after number 1 at 1:4");
+
+        let eval = |s: &str| eval_string(&String::from(s), default_env());
+        assert!(matches!(eval("(- 3 2)"), Ok(Item::Number(1))));
+        assert!(matches!(eval("(* -3 2)"), Ok(Item::Number(-6))));
+        assert!(matches!(eval("(- 3 -2)"), Ok(Item::Number(5))));
+        match eval("(* 1e-3 .5)") {
+            Ok(Item::Float(num)) => assert!(f32::abs(num - 0.0005) < 1e-9),
+            other => panic!("{:?}", other)
+        }
+        match eval("(+ -2.5e1 -.5)") {
+            Ok(Item::Float(num)) => assert!(f32::abs(num + 25.5) < 1e-6),
+            other => panic!("{:?}", other)
+        }
+        assert!(eval("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn identifiers() {
+        use lexer::{lex, TokenKind};
+        use parser::parse_string;
+        use program::Operator;
+
+        let tokens = lex("(freqA set! list? ->db +gain <=> -- a.b%c)").unwrap();
+        let texts = tokens.iter().map(|token| token.text.as_str()).collect::<Vec<&str>>();
+        assert_eq!(texts, vec!["(", "freqA", "set!", "list?", "->db", "+gain", "<=>", "--", "a.b%c", ")"]);
+        assert!(tokens[1..9].iter().all(|token| token.kind == TokenKind::Identifier));
+
+        let items = match parse_string(String::from("(<= <=> -- - +gain)")) {
+            Ok(Item::List(list, _)) => match list.cdr().car() {
+                Some(Item::List(inner, _)) => inner.iter().cloned().collect::<Vec<Item>>(),
+                other => panic!("{:?}", other)
+            },
+            other => panic!("{:?}", other)
+        };
+        assert!(matches!(items[0], Item::Operator(Operator::BinaryComparator(_))));
+        assert!(matches!(&items[1], Item::Identifier(name, _) if name == "<=>"));
+        assert!(matches!(&items[2], Item::Identifier(name, _) if name == "--"));
+        assert!(matches!(items[3], Item::Operator(Operator::BinaryOperator(_))));
+        assert!(matches!(&items[4], Item::Identifier(name, _) if name == "+gain"));
+
+        let program = "(let (freqA 2) (->db (func (x) (* x 10))) (->db freqA))";
+        assert!(matches!(eval_string(&String::from(program), default_env()), Ok(Item::Number(20))));
+    }
+
+    #[test]
+    fn symbols() {
+        use symbol::Symbol;
+        use parser::parse_string;
+
+        let freq = Symbol::intern("freq");
+        assert_eq!(freq, Symbol::intern("freq"));
+        assert_eq!(freq, Symbol::from("freq"));
+        assert_ne!(freq, Symbol::intern("Freq"));
+        assert_eq!(freq.as_str(), "freq");
+        assert_eq!(freq.to_string(), "freq");
+
+        // every occurrence of a name in a program shares one symbol
+        let idents = match parse_string(String::from("(freq gain freq)")) {
+            Ok(Item::List(list, _)) => match list.cdr().car() {
+                Some(Item::List(inner, _)) => inner.iter().map(|item| match item {
+                    Item::Identifier(symbol, _) => *symbol,
+                    other => panic!("{:?}", other)
+                }).collect::<Vec<Symbol>>(),
+                other => panic!("{:?}", other)
+            },
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(idents, vec![freq, Symbol::intern("gain"), freq]);
+
+        let interned = (0..8).map(|_| std::thread::spawn(|| Symbol::intern("shared-between-threads")))
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<Symbol>>();
+        assert!(interned.iter().all(|symbol| *symbol == interned[0]));
+    }
+
+    #[test]
+    fn error_locations() {
+        let missing = eval_string(&String::from("(let (x 4)\n  (* x foo))"), default_env());
+        assert_eq!(missing.map_err(|err| err.to_string()).err(), Some(String::from("Identifier not found: foo at 2:8")));
+    }
+
+    #[test]
+    fn error_kinds() {
+        use error::{ErrorKind, NmError};
+        use lexer::Span;
+
+        let kind = |s: &str| eval_string(&String::from(s), default_env()).err().map(|err| err.kind);
+
+        assert!(matches!(kind("(+ 1 ,)"), Some(ErrorKind::Lex(_))));
+        assert!(matches!(kind("\"bad \\q\""), Some(ErrorKind::Parse(_))));
+        assert!(matches!(kind("(let (x) x)"), Some(ErrorKind::Syntax(_))));
+        assert!(matches!(kind("(* y 2)"), Some(ErrorKind::Unbound(name)) if name == "y"));
+        assert!(matches!(kind("(+ 1 \"two\")"), Some(ErrorKind::TypeMismatch(_))));
+        assert!(matches!(kind("(if 1 2 3)"), Some(ErrorKind::TypeMismatch(_))));
+        assert!(matches!(kind("(< 3)"), Some(ErrorKind::Arity { min: 2, given: 1, .. })));
+        assert!(matches!(kind("(/ 7 0)"), Some(ErrorKind::DivisionByZero)));
+        assert!(matches!(kind("(* 9223372036854775807 2)"), Some(ErrorKind::Overflow(_))));
+        assert!(matches!(kind("(error \"volume\" 11 \"is too loud\")"), Some(ErrorKind::User(msg)) if msg == "volume 11 is too loud"));
+
+        let err = eval_string(&String::from("(progn\n  (/ 1 0))"), default_env()).unwrap_err();
+        assert_eq!(err.span, Some(Span { offset: 9, line: 2, column: 3 }));
+        assert_eq!(err.to_string(), "Division by zero at 2:3");
+        assert_eq!(NmError::arity(String::from("cat"), 2, Some(2), 1, Span::default()).to_string(), "cat expects 2 arguments, 1 given at 1:1");
+
+        let lexed: Box<dyn std::error::Error> = Box::new(eval_string(&String::from("#"), default_env()).unwrap_err());
+        assert!(lexed.source().is_some());
+
+        let nested = "(letrec (f (func (x) (* x \"missing\"))) (g (func (y) (+ 1 (f y)))) (g 2))";
+        let err = eval_string(&String::from(nested), default_env()).unwrap_err();
+        let trace = err.trace.iter().map(|frame| frame.function.as_str()).collect::<Vec<&str>>();
+        assert_eq!(trace, vec!["f", "g"]);
+    }
+
+    #[test]
+    fn backtraces() {
+        let program = "(letrec\n  (fac (func (x)\n    (if (<= x 1) (/ 1 (- x x)) (* x (fac (- x 1))))))\n  (fac 3))";
+        let err = eval_string(&String::from(program), default_env()).unwrap_err();
+        assert_eq!(err.to_string(), "Division by zero at 3:18");
+        assert_eq!(err.backtrace(), "  0: (fac 1) at 3:37\n  1: (fac 2) at 3:37\n  2: (fac 3) at 4:3\n");
+
+        let passed = "(let (fac (func (x) (/ x 0))) (apply (func (g n) (g n))) (apply fac 3))";
+        let err = eval_string(&String::from(passed), default_env()).unwrap_err();
+        let names = err.trace.iter().map(|frame| frame.function.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["fac", "apply"]);
+
+        assert!(matches!(err.trace[0].args.as_slice(), [Item::Number(3)]));
+        assert!(matches!(err.trace[1].args.as_slice(), [Item::Function(_), Item::Number(3)]));
+
+        let anonymous = eval_string(&String::from("((func (x) (+ x \"nope\")) 1.5)"), default_env()).unwrap_err();
+        assert_eq!(anonymous.backtrace(), "  0: (lambda 1.5) at 1:1\n");
+        assert!(eval_string(&String::from("(+ 1 nope)"), default_env()).unwrap_err().trace.is_empty());
+    }
+
+    #[test]
+    fn unbalanced_parens() {
+        use parser::parse_string;
+
+        let message = |s: &str| parse_string(String::from(s)).map(|_| ()).map_err(|err| err.to_string());
+
+        assert_eq!(message("(+ 1 2"), Err(String::from("Parse error: unmatched ( is never closed at 1:1")));
+        assert_eq!(message("(+ 1 2))"), Err(String::from("Parse error: unmatched ) has no opening paren at 1:8")));
+        assert_eq!(message("(print\n  '(1 2 (3)"), Err(String::from("Parse error: unmatched '( is never closed at 2:3")));
+        assert_eq!(message("(let (x 1)\n  (* x (+ x 2))"), Err(String::from("Parse error: unmatched ( is never closed at 1:1")));
+        assert_eq!(message(")"), Err(String::from("Parse error: unmatched ) has no opening paren at 1:1")));
+        assert_eq!(message("(define (f x)\n(+ x 1))\n(print (f 2)\n(g 3)"), Err(String::from("Parse error: unmatched ( is never closed at 3:1")));
+        assert_eq!(message("(+ 1 2) (* 3 4)"), Ok(()));
+        assert_eq!(message("'(1 (2 3) 4)"), Ok(()));
+
+        let items = match parse_string(String::from("(a (b c) '(d))")) {
+            Ok(Item::List(list, _)) => match list.cdr().car() {
+                Some(Item::List(inner, _)) => inner.iter().cloned().collect::<Vec<Item>>(),
+                other => panic!("{:?}", other)
+            },
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(format!("{:?}", items), "[a, (b c), '(d)]");
+    }
+
+    #[test]
+    fn parse_recovery() {
+        use lexer::{lex, lex_recovering};
+        use parser::parse_recovering;
+
+        let source = "(print \"ok\")\n)\n(let (x 1)\n  (* x (+ x 2)\n(print \"bad \\q\" 99999999999999999999)\n)\n(cat \"a\" \"b\")\n(+ 1 2";
+        let result = parse_recovering(&lex(source).unwrap());
+        let messages = result.diagnostics.iter().map(|err| err.to_string()).collect::<Vec<String>>();
+        assert_eq!(messages, vec![
+            "Parse error: unmatched ) has no opening paren at 2:1",
+            "Parse error: unknown escape \\q in string at 5:8",
+            "Parse error: integer literal 99999999999999999999 out of range at 5:17",
+            "Parse error: unmatched ( is never closed at 3:1",
+            "Parse error: unmatched ( is never closed at 8:1",
+        ]);
+        assert_eq!(format!("{:?}", result.program), "(Progn (Print \"ok\") (Cat \"a\" \"b\"))");
+
+        // only parens that are really unclosed end a form at the start of a line
+        let result = parse_recovering(&lex("(define (f x)\n(+ x 1))\n(print (f 2)\n(g 3)").unwrap());
+        let messages = result.diagnostics.iter().map(|err| err.to_string()).collect::<Vec<String>>();
+        assert_eq!(messages, vec!["Parse error: unmatched ( is never closed at 3:1"]);
+        assert_eq!(format!("{:?}", result.program), "(Progn (Define (f x) (BinaryOperator(Add) x 1)) (g 3))");
+        let result = parse_recovering(&lex("(a (b\n(c))\n(d)").unwrap());
+        let messages = result.diagnostics.iter().map(|err| err.to_string()).collect::<Vec<String>>();
+        assert_eq!(messages, vec!["Parse error: unmatched ( is never closed at 1:1"]);
+        assert_eq!(format!("{:?}", result.program), "(Progn (d))");
+
+        // text that is not a token only spoils the form it is in
+        let result = parse_recovering(&lex_recovering("(print 1)\n(+ 1 [)\n(* 2 1x)\n(- 3)\n#; (oops ,)\n\"open"));
+        let messages = result.diagnostics.iter().map(|err| err.to_string()).collect::<Vec<String>>();
+        assert_eq!(messages, vec![
+            "Unexpected character '[' at 2:6",
+            "Unexpected character 'x' after number 1 at 3:6",
+            "Unexpected character ',' at 5:10",
+            "Unexpected character '\"' at 6:1",
+        ]);
+        assert_eq!(format!("{:?}", result.program), "(Progn (Print 1) (BinaryOperator(Sub) 3))");
+        assert_eq!(lex("(+ 1 [)").unwrap_err().to_string(), "Unexpected character '[' at 1:6");
+
+        let clean = parse_recovering(&lex("(let\n(x 1)\nx)").unwrap());
+        assert!(clean.diagnostics.is_empty());
+        assert_eq!(format!("{:?}", clean.program), "(Progn (Let (x 1) x))");
+    }
+
     #[test]
     fn lists() {
         use list::List;
@@ -77,15 +421,16 @@ mod tests {
 
     #[test]
     fn nil_programs() {
+        use lexer::lex;
         use parser::parse;
         use program::{Item, Operator};
         use list::List;
 
-        let prog = parse(vec!["(", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog = parse(lex("()").unwrap()).unwrap();
         let open_close = match prog {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         list.cdr().car().is_none() // we have this structure (())
                     },
                     _ => false
@@ -95,11 +440,11 @@ mod tests {
         };
         assert!(open_close);
 
-        let prog2 = parse(vec!["(", "nil", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog2 = parse(lex("(nil)").unwrap()).unwrap();
         let nil = match prog2 {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         let first = match list.car() {
                             Some(Item::Nil) => true,
                             _ => false
@@ -113,11 +458,11 @@ mod tests {
         };
         assert!(nil);
 
-        let prog2 = parse(vec!["(", "nil", "nil", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog2 = parse(lex("(nil nil)").unwrap()).unwrap();
         let first_nil = match prog2 {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         let first = match list.car() {
                             Some(Item::Nil) => true,
                             _ => false
@@ -137,18 +482,18 @@ mod tests {
         };
         assert!(first_nil);
 
-        let prog3 = parse(vec!["(", "nil", "(", "nil", ")", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog3 = parse(lex("(nil (nil))").unwrap()).unwrap();
         let nested_nil = match prog3 {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         let first = match list.car() {
                             Some(Item::Nil) => true,
                             _ => false
                         };
 
                         let second = match list.cdr().car() {
-                            Some(Item::List(_)) => true,
+                            Some(Item::List(..)) => true,
                             _ => false
                         };
 
@@ -164,27 +509,28 @@ mod tests {
 
     #[test]
     fn number_programs() {
+        use lexer::lex;
         use parser::parse;
         use list::List;
         use program::Item;
 
-        let prog = parse(vec!["(", "4.3", "(", "5", ")", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog = parse(lex("(4.3 (5))").unwrap()).unwrap();
         let nested_numbers = match prog {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         let first = match list.car() {
                             Some(Item::Float(float)) => f32::abs(float - 4.3) < 0.01,
                             _ => false
                         };
 
                         let second = match list.cdr().car() {
-                            Some(Item::List(_)) => true,
+                            Some(Item::List(..)) => true,
                             _ => false
                         };
 
                         let third = match list.cdr().car() {
-                            Some(Item::List(list)) => { 
+                            Some(Item::List(list, _)) => { 
                                 matches!(list.car(), Some(Item::Number(5)))
                             },
                             _ => false
@@ -199,11 +545,11 @@ mod tests {
         };
         assert!(nested_numbers);
 
-        let prog2 = parse(vec!["(", "56.2", "43.8", ")"].iter().map(|s| String::from(*s)).collect()).unwrap();
+        let prog2 = parse(lex("(56.2 43.8)").unwrap()).unwrap();
         let floats = match prog2 {
-            Item::List(list_outer) => {
+            Item::List(list_outer, _) => {
                 match list_outer.cdr().car() {
-                    Some(Item::List(list)) => {
+                    Some(Item::List(list, _)) => {
                         let first_match = match list.car() {
                             Some(Item::Float(num)) => f32::abs(56.2 - num) < 0.001,
                             _ => false
@@ -226,61 +572,61 @@ mod tests {
 
     #[test]
     fn arithmetic() {
-        use eval::{eval, eval_string};
+        use eval::eval;
         use program::{Item, Operator};
         use list::List;
 
-        let mut env = List::new();
+        let mut env = program::Env::default();
         match eval_string(&format!("(* 3 2)"), env) {
             Ok(Item::Number(num)) => assert!(num == 6),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(1 == 2)
         }
 
-        env = List::new();
+        env = program::Env::default();
         match eval_string(&format!("(+ 3 2)"), env) {
             Ok(Item::Number(num)) => assert!(num == 5),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(1 == 2)
         }
 
-        env = List::new();
+        env = program::Env::default();
         match eval_string(&format!("(/ 10 2)"), env) {
             Ok(Item::Number(num)) => assert!(num == 5),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(1 == 2)
         }
 
-        env = List::new();
+        env = program::Env::default();
         match eval_string(&format!("(- 10 2)"), env) {
             Ok(Item::Number(num)) => assert!(num == 8),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(1 == 2)
         }
     }
 
     #[test]
     fn math_functions() {
-        use eval::{eval, eval_string, default_env};
+        use eval::{eval, default_env};
         use program::{Item, Operator};
         use list::List;
 
         let mut env = default_env();
         match eval_string(&format!("(* e 2.0)"), env) {
             Ok(Item::Float(num)) => assert!(f32::abs((E * 2.0) - num) < 0.01),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(false)
         }
 
         let mut env = default_env();
         match eval_string(&format!("(* pi 3.4)"), env) {
             Ok(Item::Float(num)) => assert!(f32::abs((PI * 3.4) - num) < 0.01),
-            Err(msg) => assert!(msg.as_str() == ""),
+            Err(err) => panic!("{}", err),
             _ => assert!(false)
         }
 
         let fact_program = 
-            "(let (fac
+            "(letrec (fac
                 (func (x)
                     (if (<= x 1) 1 (* x (fac (- x 1))))))
                 (fac 5)
@@ -292,9 +638,120 @@ mod tests {
         assert!(factorial);
     }
 
+    #[test]
+    fn numeric_promotion() {
+        let eval = |s: &str| format!("{:?}", eval_string(s, default_env()).unwrap());
+
+        // integers stay integers, dividing towards zero
+        assert_eq!(eval("(* 2 3)"), "6");
+        assert_eq!(eval("(/ 7 2)"), "3");
+        assert_eq!(eval("(/ -7 2)"), "-3");
+
+        // an integer meeting a float becomes a float, on either side
+        assert_eq!(eval("(* 2 1.5)"), "3.0");
+        assert_eq!(eval("(+ 1.5 2)"), "3.5");
+        assert_eq!(eval("(- 1 0.25)"), "0.75");
+        assert_eq!(eval("(/ 7 2.0)"), "3.5");
+        assert_eq!(eval("(/ 1 0.0)"), "inf");
+        assert_eq!(eval("(let (square (func (x) (* x x))) (+ (square 3) (square 0.5)))"), "9.25");
+
+        // comparisons promote the same way, and == between floats is approximate
+        assert_eq!(eval("(< 1 1.5)"), "true");
+        assert_eq!(eval("(>= 2.0 2)"), "true");
+        assert_eq!(eval("(== 3 3.0)"), "true");
+        assert_eq!(eval("(!= 3 3.5)"), "true");
+        assert_eq!(eval("(== 1 1.0000001)"), "true");
+        assert_eq!(eval("(!= 1 1.0000001)"), "false");
+        for (a, b) in [("1", "1.0"), ("1", "1.0000001"), ("0.1", "0.1000001"), ("2", "2.5"), ("-0.0", "0.0")] {
+            let eq = eval(&format!("(== {} {})", a, b));
+            let neq = eval(&format!("(!= {} {})", a, b));
+            assert_ne!(eq, neq, "!= is not the negation of == for {} and {}", a, b);
+        }
+
+        // unary math always gives a float
+        assert_eq!(eval("(exp 0)"), "1.0");
+        assert_eq!(eval("(rec 4)"), "0.25");
+        assert_eq!(eval("(sin 0.0)"), "0.0");
+
+        // anything else is still an error
+        let err = eval_string("(+ 1 \"two\")", default_env()).unwrap_err();
+        assert_eq!(err.to_string(), "Type error: arguments 1, \"two\" are not both numbers at 1:1");
+        assert!(eval_string("(< 1 true)", default_env()).is_err());
+        assert!(eval_string("(/ 1 0)", default_env()).is_err());
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let fac = |n: i64| eval_string(&format!("(letrec (fac (func (x) (if (<= x 1) 1 (* x (fac (- x 1)))))) (fac {}))", n), default_env());
+
+        assert!(matches!(fac(13), Ok(Item::Number(6227020800))));
+        assert!(matches!(fac(20), Ok(Item::Number(2432902008176640000))));
+        let err = fac(21).unwrap_err();
+        assert_eq!(err.to_string(), "Integer overflow: (* 21 2432902008176640000) at 1:39");
+        assert_eq!(err.trace[0].function, "fac");
+
+        let eval = |s: &str| eval_string(s, default_env()).map_err(|err| err.to_string());
+        assert!(matches!(eval("(+ 9223372036854775806 1)"), Ok(Item::Number(i64::MAX))));
+        assert_eq!(eval("(- -9223372036854775807 2)").unwrap_err(), "Integer overflow: (- -9223372036854775807 2) at 1:1");
+        assert_eq!(eval("(/ -9223372036854775808 -1)").unwrap_err(), "Integer overflow: (/ -9223372036854775808 -1) at 1:1");
+        assert_eq!(eval("(/ 1 0)").unwrap_err(), "Division by zero at 1:1");
+
+        // floats are not checked
+        assert!(matches!(eval("(* 9223372036854775807 2.0)"), Ok(Item::Float(_))));
+    }
+
+    #[test]
+    fn variadic_operators() {
+        let eval = |s: &str| format!("{:?}", eval_string(s, default_env()).unwrap());
+        let err = |s: &str| eval_string(s, default_env()).unwrap_err().to_string();
+
+        // arithmetic works from left to right over any number of operands
+        assert_eq!(eval("(+ 1 2 3)"), "6");
+        assert_eq!(eval("(- 10 1 2 3)"), "4");
+        assert_eq!(eval("(* 2 3 4.0)"), "24.0");
+        assert_eq!(eval("(/ 100 5 2)"), "10");
+        assert_eq!(eval("(+)"), "0");
+        assert_eq!(eval("(*)"), "1");
+        assert_eq!(eval("(+ 5)"), "5");
+        assert_eq!(eval("(- 5)"), "-5");
+        assert_eq!(eval("(- 2.5)"), "-2.5");
+        assert_eq!(eval("(/ 4.0)"), "0.25");
+        assert_eq!(err("(-)"), "- expects at least 1 argument, 0 given at 1:1");
+        assert_eq!(err("(/ 1 2 0)"), "Division by zero at 1:1");
+
+        // comparisons hold if they hold for each adjacent pair
+        assert_eq!(eval("(< 1 2 3)"), "true");
+        assert_eq!(eval("(< 1 3 2)"), "false");
+        assert_eq!(eval("(<= 1 1 2.5)"), "true");
+        assert_eq!(eval("(== 2 2 2)"), "true");
+        assert_eq!(eval("(> 3 2 2)"), "false");
+        assert_eq!(err("(< 1)"), "< expects at least 2 arguments, 1 given at 1:1");
+
+        assert_eq!(eval("(and true true false)"), "false");
+        assert_eq!(eval("(and true true true)"), "true");
+        assert_eq!(eval("(or false false true)"), "true");
+        assert_eq!(eval("(and)"), "true");
+        assert_eq!(eval("(or)"), "false");
+        assert!(eval_string("(and true true 1)", default_env()).is_err());
+
+        // and stops at the first false operand, or at the first true one
+        assert_eq!(eval("(and false (/ 1 0))"), "false");
+        assert_eq!(eval("(or (< 1 2) (/ 1 0))"), "true");
+        assert_eq!(eval("(and true (or false true) (== 1 1) false (error \"unreachable\"))"), "false");
+        assert_eq!(err("(and true (/ 1 0) false)"), "Division by zero at 1:11");
+        assert_eq!(err("(or false (/ 1 0))"), "Division by zero at 1:11");
+
+        // operators taking one operand do not ignore extra ones
+        assert_eq!(err("(not true false)"), "not expects 1 argument, 2 given at 1:1");
+        assert_eq!(err("(sin 1.0 2.0)"), "sin expects 1 argument, 2 given at 1:1");
+
+        // an operator passed around as a value takes the same operands
+        assert_eq!(eval("(let (sum (func (op) (op 1 2 3 4))) (sum +))"), "10");
+    }
+
     #[test]
     fn lets_and_funcs() {
-        use eval::{eval, eval_string, default_env};
+        use eval::{eval, default_env};
         use program::{Item, Operator};
         use list::List;
 
@@ -342,6 +799,315 @@ mod tests {
 
     }
 
+    #[test]
+    fn closures() {
+        let eval = |s: &str| match eval_string(s, default_env()) {
+            Ok(item) => item,
+            Err(err) => panic!("{}", err)
+        };
+
+        // a returned function keeps the environment it was made in
+        let make_adder = "(letrec (make-adder (func (n) (func (x) (+ x n)))) \
+                               (add5 (make-adder 5)) \
+                               (n 100) \
+                               (add5 1))";
+        assert!(matches!(eval(make_adder), Item::Number(6)));
+
+        // the caller's bindings are not visible inside the function
+        let lexical = "(letrec (y 1) \
+                            (get-y (func () y)) \
+                            (let (y 2) (get-y)))";
+        assert!(matches!(eval(lexical), Item::Number(1)));
+
+        let leak = "(let (f (func () hidden)) \
+                         (let (hidden 3) (f)))";
+        assert!(matches!(eval_string(leak, default_env()), Err(err) if matches!(&err.kind, error::ErrorKind::Unbound(name) if name == "hidden")));
+
+        // let evaluates every value before binding any of them, letrec binds in order
+        assert!(matches!(eval("(let (a 2) (let (a 1) (b (* a 3)) (+ a b)))"), Item::Number(7)));
+        assert!(matches!(eval("(let (a 1) (let (a (+ a 1)) (b a) (+ a b)))"), Item::Number(3)));
+        assert!(matches!(eval_string("(let (a 2) (b (* a 3)) b)", default_env()), Err(err) if matches!(&err.kind, error::ErrorKind::Unbound(name) if name == "a")));
+        assert!(matches!(eval("(letrec (a 2) (b (* a 3)) (+ a b))"), Item::Number(8)));
+
+        let recursive = "(letrec (fac (func (n) (if (< n 2) 1 (* n (fac (- n 1)))))) (fac 5))";
+        assert!(matches!(eval(recursive), Item::Number(120)));
+
+        let mutual = "(letrec (even? (func (n) (if (== n 0) true (odd? (- n 1))))) \
+                              (odd? (func (n) (if (== n 0) false (even? (- n 1))))) \
+                              (even? 10))";
+        assert!(matches!(eval(mutual), Item::Boolean(true)));
+
+        // a plain let is not recursive, so a function can wrap one it shadows
+        let shadowing = "(let (f (func (x) x)) (let (f (func (y) (f y))) (f 3)))";
+        assert!(matches!(eval(shadowing), Item::Number(3)));
+        let unbound = "(let (fac (func (n) (if (< n 2) 1 (* n (fac (- n 1)))))) (fac 5))";
+        assert!(matches!(eval_string(unbound, default_env()), Err(err) if matches!(&err.kind, error::ErrorKind::Unbound(name) if name == "fac")));
+
+        // functions defined in a recursive group still capture their surroundings
+        let captured = "(letrec (step 3) \
+                             (count (func (n acc) (if (< n 1) acc (count (- n step) (+ acc 1))))) \
+                             (count 9 0))";
+        assert!(matches!(eval(captured), Item::Number(3)));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let eval = |s: &str| match eval_string(s, default_env()) {
+            Ok(item) => format!("{:?}", item),
+            Err(err) => panic!("{}", err)
+        };
+
+        // every one of these would overflow the stack without tail calls
+        let countdown = "(letrec (loop (func (n) (if (== n 0) \"done\" (loop (- n 1))))) (loop 100000))";
+        assert_eq!(eval(countdown), "\"done\"");
+
+        let through_let_and_progn = "(letrec (sum (func (n acc) \
+                                              (let (next (- n 1)) \
+                                                   (progn (if (< n 1) acc (sum next (+ acc 1))))))) \
+                                          (sum 100000 0))";
+        assert_eq!(eval(through_let_and_progn), "100000");
+
+        let mutual = "(letrec (ping (func (n) (if (== n 0) true (pong (- n 1))))) \
+                           (pong (func (n) (if (== n 0) false (ping (- n 1))))) \
+                           (ping 100001))";
+        assert_eq!(eval(mutual), "false");
+
+        // the most recent tail calls still show up in backtraces
+        let failing = "(letrec (loop (func (n) (if (== n 0) (/ 1 n) (loop (- n 1))))) (loop 1000))";
+        let err = eval_string(failing, default_env()).unwrap_err();
+        assert!(matches!(err.kind, error::ErrorKind::DivisionByZero));
+        assert!(matches!(err.trace[0].args.as_slice(), [Item::Number(0)]));
+        assert!(matches!(err.trace[1].args.as_slice(), [Item::Number(1)]));
+        assert!(err.trace.len() < 100);
+    }
+
+    #[test]
+    fn globals() {
+        use error::ErrorKind;
+        use symbol::Symbol;
+
+        let env = default_env();
+        let eval = |s: &str| match eval_string(s, env.clone()) {
+            Ok(item) => format!("{:?}", item),
+            Err(err) => panic!("{}", err)
+        };
+
+        let program = "(define rate 48000) \
+                       (define (seconds n) (* n rate)) \
+                       (define (fac n) (if (< n 2) 1 (* n (fac (- n 1))))) \
+                       (seconds 2)";
+        assert_eq!(eval(program), "96000");
+
+        // definitions outlive the program that made them
+        assert_eq!(eval("(fac 5)"), "120");
+        assert_eq!(eval("(progn (set! rate 44100) (seconds 1))"), "44100");
+        assert_eq!(eval("rate"), "44100");
+
+        // functions see later definitions and changes made after they were defined
+        assert_eq!(eval("(progn (define (twice x) (double (double x))) (define double (func (x) (* 2 x))) (twice 3))"), "12");
+        assert!(matches!(env.lookup(Symbol::intern("double")), Some(Item::Function(closure)) if closure.lambda.name == Some(Symbol::intern("double"))));
+
+        // closures made inside a let still capture their locals
+        assert_eq!(eval("(let (offset 10) (define (shift x) (+ x offset)))"), "nil");
+        assert_eq!(eval("(shift 1)"), "11");
+
+        let error = |s: &str| eval_string(s, env.clone()).unwrap_err().kind;
+        assert!(matches!(error("(set! undefined 1)"), ErrorKind::Unbound(name) if name == "undefined"));
+        assert!(matches!(error("(let (rate 1) (set! rate 2))"), ErrorKind::Syntax(_)));
+        assert!(matches!(error("(define 3 4)"), ErrorKind::Syntax(_)));
+
+        // a fresh environment knows nothing about the other one's definitions
+        assert!(eval_string("(fac 3)", default_env()).is_err());
+    }
+
+    #[test]
+    fn interpreter_sessions() {
+        use interpreter::Interpreter;
+        use parser::parse_string;
+
+        let nm = Interpreter::new();
+        nm.define_native("f", Item::Float(440.0));
+        assert!(matches!(nm.eval_str("(define (tone t) (* (sin (* t f)) 0.5))"), Ok(Item::Nil)));
+        assert!(matches!(nm.get_global("f"), Some(Item::Float(f)) if f == 440.0));
+        assert!(matches!(nm.get_global("tone"), Some(Item::Function(_))));
+        assert!(nm.get_global("t").is_none());
+
+        // a parsed expression can be evaluated many times with different locals
+        let signal = parse_string(String::from("(tone t)")).unwrap();
+        for t in [0.0, 0.25, 0.5] {
+            match nm.eval_with(&signal, &[("t", Item::Float(t))]) {
+                Ok(Item::Float(value)) => assert!(f32::abs(value - f32::sin(t * 440.0) * 0.5) < 1e-6),
+                other => panic!("{:?}", other)
+            }
+        }
+        assert!(nm.eval_str("t").is_err());
+
+        let path = std::env::temp_dir().join(format!("nm-interpreter-{}.nm", std::process::id()));
+        std::fs::write(&path, "(define gain 0.5)\n(define (amp x) (* x gain))\n(amp 4.0)").unwrap();
+        let loaded = nm.load_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(loaded, Ok(Item::Float(value)) if value == 2.0));
+        assert!(matches!(nm.eval_str("(amp 3.0)"), Ok(Item::Float(value)) if value == 1.5));
+
+        let missing = nm.load_file("/definitely/not/a/patch.nm").unwrap_err();
+        assert!(matches!(missing.kind, error::ErrorKind::Io(_)));
+    }
+
+    #[test]
+    fn native_functions() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        use error::{ErrorKind, NmError};
+        use interpreter::Interpreter;
+        use program::NativeFn;
+
+        let nm = Interpreter::new();
+        nm.register_fn("clamp", 3, |args| match args {
+            [Item::Float(x), Item::Float(lo), Item::Float(hi)] => Ok(Item::Float(x.clamp(*lo, *hi))),
+            _ => Err(NmError::new(ErrorKind::TypeMismatch(String::from("clamp takes three floats")), None))
+        });
+        nm.define_native("sum", Item::NativeFn(Arc::new(NativeFn::new("sum", 0, None, |args| {
+            Ok(Item::Number(args.iter().map(|arg| if let Item::Number(n) = arg { *n } else { 0 }).sum()))
+        }))));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        nm.register_fn("tick", 0, move |_| Ok(Item::Number(counter.fetch_add(1, Ordering::SeqCst) as i64)));
+
+        let eval = |s: &str| match nm.eval_str(s) {
+            Ok(item) => format!("{:?}", item),
+            Err(err) => panic!("{}", err)
+        };
+        assert_eq!(eval("(clamp 1.5 0.0 1.0)"), "1.0");
+        assert_eq!(eval("(sum 1 2 3 4)"), "10");
+        assert_eq!(eval("(sum)"), "0");
+        assert_eq!(eval("(progn (tick) (tick) (tick))"), "2");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // natives are ordinary values
+        assert_eq!(eval("(let (apply (func (f x) (f x 0.0 0.5))) (apply clamp 0.75))"), "0.5");
+        assert_eq!(eval("clamp"), "native(clamp)");
+
+        let err = nm.eval_str("(clamp 1.0 2.0)").unwrap_err();
+        assert_eq!(err.to_string(), "clamp expects 3 arguments, 2 given at 1:1");
+
+        let err = nm.eval_str("(let (safe (func (x) (clamp x 0 1))) (safe 2))").unwrap_err();
+        assert_eq!(err.to_string(), "Type error: clamp takes three floats at 1:22");
+        let names = err.trace.iter().map(|frame| frame.function.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["clamp", "safe"]);
+    }
+
+    #[test]
+    fn resolution() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        use error::ErrorKind;
+        use interpreter::Interpreter;
+        use parser::parse_string;
+        use program::Address;
+        use resolver::resolve;
+
+        fn addresses(item: &Item, out: &mut Vec<String>) {
+            match item {
+                Item::Local(name, Address { depth, index }, _) => out.push(format!("{}@{}.{}", name, depth, index)),
+                Item::Global(name, _, _) => out.push(format!("{}@global", name)),
+                Item::List(list, _) => list.iter().for_each(|item| addresses(item, out)),
+                _ => ()
+            }
+        }
+        let resolved = |s: &str| {
+            let env = default_env();
+            let mut out = Vec::new();
+            addresses(&resolve(&parse_string(String::from(s)).unwrap(), &env).unwrap(), &mut out);
+            out
+        };
+
+        // one frame per let, per letrec binding, per letrec group of functions and per call
+        assert_eq!(resolved("(let (x 1) (f (func (y) y)) (f x pi))"), vec!["y@0.0", "f@0.1", "x@0.0", "pi@global"]);
+        assert_eq!(resolved("(letrec (x 1) (f (func (y) (+ x y))) (f pi))"), vec!["x@2.0", "y@0.0", "f@0.0", "pi@global"]);
+        assert_eq!(resolved("(let (a 1) (b 2) (+ a b))"), vec!["a@0.0", "b@0.1"]);
+        assert_eq!(resolved("(letrec (a 1) (b 2) (+ a b))"), vec!["a@1.0", "b@0.0"]);
+        assert_eq!(resolved("((func (a &optional (b a) &rest c) (a b c)) 1)"), vec!["a@0.0", "a@0.0", "b@0.1", "c@0.2"]);
+        assert_eq!(resolved("(letrec (even? (func (n) (odd? n))) (odd? (func (n) (even? n))) (even? 1))"),
+            vec!["odd?@1.1", "n@0.0", "even?@1.0", "n@0.0", "even?@0.0"]);
+        // quoted lists are data
+        assert!(resolved("'(nowhere to be found)").is_empty());
+
+        // unbound names outside functions are found without running anything
+        let nm = Interpreter::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        nm.register_fn("tick", 0, move |_| Ok(Item::Number(counter.fetch_add(1, Ordering::SeqCst) as i64)));
+        let err = nm.eval_str("(progn (tick) (+ 1 undefined) (tick))").unwrap_err();
+        assert_eq!(err.to_string(), "Identifier not found: undefined at 1:20");
+        assert_eq!(ticks.load(Ordering::SeqCst), 0);
+
+        // functions may use globals a later program defines, and are checked when called
+        assert!(nm.eval_str("(define (later) (+ 1 undefined))").is_ok());
+        assert_eq!(nm.eval_str("(later)").unwrap_err().to_string(), "Identifier not found: undefined at 1:22");
+        assert!(matches!(nm.eval_str("(define undefined 2) (later)"), Ok(Item::Number(3))));
+        assert!(nm.eval_str("(define (first) (second))").is_ok());
+        assert!(nm.eval_str("(define (second) 5)").is_ok());
+        assert!(matches!(nm.eval_str("(first)"), Ok(Item::Number(5))));
+        // other functions cannot be called by a later program, so typos in them are still caught
+        let err = nm.eval_str("(let (f (func (x) (+ x typo))) 3)").unwrap_err();
+        assert_eq!(err.to_string(), "Identifier not found: typo at 1:24");
+        assert!(matches!(nm.eval_str("((func () (missing)))").unwrap_err().kind, ErrorKind::Unbound(name) if name == "missing"));
+
+        // but defines anywhere in the program count, so forward references work
+        assert!(matches!(nm.eval_str("(define (f) (g)) (define (g) 7) (f)"), Ok(Item::Number(7))));
+        assert!(matches!(nm.eval_str("(f)"), Ok(Item::Number(7))));
+
+        // hosts resolve once and then evaluate with their own locals
+        nm.define_native("f", Item::Float(440.0));
+        let signal = parse_string(String::from("(* t f)")).unwrap();
+        assert!(matches!(nm.resolve(&signal, &[]).unwrap_err().kind, ErrorKind::Unbound(name) if name == "t"));
+        let signal = nm.resolve(&signal, &["t"]).unwrap();
+        assert!(matches!(nm.eval_with(&signal, &[("t", Item::Float(0.5))]), Ok(Item::Float(value)) if value == 220.0));
+    }
+
+    #[test]
+    fn function_arity() {
+        use error::ErrorKind;
+
+        let eval = |s: &str| match eval_string(s, default_env()) {
+            Ok(item) => format!("{:?}", item),
+            Err(err) => panic!("{}", err)
+        };
+        let error = |s: &str| match eval_string(s, default_env()) {
+            Ok(item) => panic!("expected an error, got {:?}", item),
+            Err(err) => err
+        };
+
+        let err = error("(let (square (func (x) (* x x))) (square 2 2))");
+        assert!(matches!(&err.kind, ErrorKind::Arity { function, min: 1, max: Some(1), given: 2 } if function == "square"));
+        assert_eq!(err.to_string(), "square expects 1 argument, 2 given at 1:34");
+
+        let err = error("(let (mod (func (x y) x)) (mod 3))");
+        assert_eq!(err.to_string(), "mod expects 2 arguments, 1 given at 1:27");
+
+        let err = error("((func (a &optional b) a))");
+        assert_eq!(err.to_string(), "lambda expects 1 to 2 arguments, 0 given at 1:1");
+
+        let err = error("(let (f (func (a b &rest r) a)) (f 1))");
+        assert_eq!(err.to_string(), "f expects at least 2 arguments, 1 given at 1:33");
+
+        // optional parameters take their default, which can see earlier parameters
+        assert_eq!(eval("(let (g (func (a &optional (b (* a 10)) c) (a b c))) (g 1))"), "'(1 10 nil)");
+        assert_eq!(eval("(let (g (func (a &optional (b 5)) (a b))) (g 1 2))"), "'(1 2)");
+
+        // &rest collects whatever is left into a list
+        assert_eq!(eval("(let (f (func (a &rest more) more)) (f 1 2 3))"), "'(2 3)");
+        assert_eq!(eval("(let (f (func (a &rest more) more)) (f 1))"), "'()");
+
+        let err = error("(func (a &rest) a)");
+        assert!(matches!(err.kind, ErrorKind::Syntax(_)));
+        let err = error("(func (a (b 1)) a)");
+        assert!(matches!(err.kind, ErrorKind::Syntax(_)));
+
+        // a parameter name can only be used once
+        assert_eq!(error("((func (a a) a) 1 2)").to_string(), "Syntax error: parameter a is named more than once at 1:8");
+        assert_eq!(error("(func (a &optional (b 1) &rest b) a)").to_string(), "Syntax error: parameter b is named more than once at 1:7");
+        assert_eq!(error("(progn (define (f x &optional x) x) (f 1))").to_string(), "Syntax error: parameter x is named more than once at 1:16");
+    }
+
     #[test]
     fn compare() {
         let three_eq_three = match eval_string(&format!("(== 3 3)"), default_env()) {
@@ -399,4 +1165,228 @@ mod tests {
         };
         assert!(or);
     }
+
+    #[test]
+    fn bytecode() {
+        use std::sync::Arc;
+        use compiler::{compile, Op};
+        use parser::parse_string;
+        use symbol::Symbol;
+
+        let run = |s: &str| match vm::run_string(s, default_env()) {
+            Ok(item) => format!("{:?}", item),
+            Err(err) => panic!("{}", err)
+        };
+        assert_eq!(run("(let (double (func (x) (* 2 x))) (double 21))"), "42");
+
+        // calls between nm functions do not grow the Rust stack, tail calls or not
+        let count = "(letrec (count (func (n) (if (== n 0) 0 (+ 1 (count (- n 1)))))) (count 100000))";
+        assert_eq!(run(count), "100000");
+
+        let failing = "(letrec (f (func (n) (if (== n 0) (/ 1 n) (+ 1 (f (- n 1)))))) (f 3))";
+        let err = vm::run_string(failing, default_env()).unwrap_err();
+        assert!(matches!(err.kind, error::ErrorKind::DivisionByZero));
+        let args = err.trace.iter().map(|frame| format!("{} {:?}", frame.function, frame.args)).collect::<Vec<String>>();
+        assert_eq!(args, vec!["f [0]", "f [1]", "f [2]", "f [3]"]);
+
+        // closures made by the same func share its compiled body
+        let env = default_env();
+        vm::run_string("(progn (define (adder n) (func (x) (+ x n))) (define add1 (adder 1)) (define add2 (adder 2)))", env.clone()).unwrap();
+        assert_eq!(format!("{:?}", vm::run_string("(+ (add1 1) (add2 1))", env.clone()).unwrap()), "5");
+        let code = |name: &str| match env.lookup(Symbol::intern(name)) {
+            Some(Item::Function(closure)) => closure.lambda.code.clone(),
+            other => panic!("{} is {:?}", name, other)
+        };
+        assert!(code("add1").get().is_some());
+        assert!(Arc::ptr_eq(&code("add1"), &code("add2")));
+
+        // forms too malformed to compile are left to eval
+        let chunk = compile(&parse_string(String::from("(if false 1)")).unwrap());
+        assert!(matches!(chunk.code.as_slice(), [Op::Eval(_), Op::Return]));
+        let err = vm::run_string("(if false 1)", default_env()).unwrap_err();
+        assert_eq!(err.to_string(), "Syntax error: if must contain statement for false evaluation at 1:1");
+    }
+
+    #[test]
+    fn signals() {
+        use error::ErrorKind;
+        use interpreter::Interpreter;
+        use parser::parse_string;
+
+        let nm = Interpreter::new();
+        nm.define_native("f", Item::Float(440.0));
+        nm.eval_str("(define (square x) (* x x))").unwrap();
+        let compile = |s: &str| nm.compile_signal(&parse_string(String::from(s)).unwrap(), &["t"]);
+
+        // a compiled signal gives the same samples as evaluating the program
+        let programs = [
+            "(* (sin (* t f)) 0.5)",
+            "(let (music (func (f t) (sin (/ (* f t) (* 2.0 pi))))) (music 440.0 t))",
+            "(letrec (gain 0.5) (amp (func (x &optional (g gain)) (* x g))) (amp (square t)))",
+            "(if (and (> t 0.1) (< t 0.3)) (exp t) (log t))",
+            "(if (< t 1) (* 2 t) (- t (/ 3 2)))",
+            "(if (and (< 0.1 t 0.3) (not (or))) (+ 0.5) (- (* t t 2) t 1))",
+        ];
+        for program in programs {
+            let signal = compile(program).unwrap();
+            let parsed = nm.resolve(&parse_string(String::from(program)).unwrap(), &["t"]).unwrap();
+            for time in [0.05, 0.2, 0.25, 1.5] {
+                match nm.eval_with(&parsed, &[("t", Item::Float(time))]) {
+                    Ok(Item::Float(expected)) => assert_eq!(signal.eval(&[time]), expected, "{} at {}", program, time),
+                    other => panic!("{} gave {:?}", program, other)
+                }
+            }
+        }
+        assert_eq!(compile("(+ 1 2)").unwrap().eval(&[0.0]), 3.0);
+        assert_eq!(compile("(* 2 (if (< t 1.0) 3 -4) (- 5 t))").unwrap().eval(&[2.0]), -24.0);
+        // an integer branch is promoted when the other gives a float
+        let mixed = compile("(if (< t 1.0) 1.0 0)").unwrap();
+        assert_eq!((mixed.eval(&[0.5]), mixed.eval(&[2.0])), (1.0, 0.0));
+        assert_eq!(compile("(+ t (if (> t 1.0) 2 0.5))").unwrap().eval(&[1.5]), 3.5);
+        assert_eq!(compile("(/ (if (< t 1.0) 7 -7) (if (> t 0.5) 2 3))").unwrap().eval(&[0.75]), 3.0);
+
+        // integer arithmetic that could fail for some sample is rejected up front,
+        // as signals cannot fail once compiled
+        let message = |s: &str| compile(s).err().map(|err| err.to_string());
+        assert_eq!(message("(+ t (/ 1 0))"), Some(String::from("Division by zero at 1:6")));
+        assert_eq!(message("(* 9223372036854775807 2)"), Some(String::from("Integer overflow: (* 9223372036854775807 2) at 1:1")));
+        assert_eq!(message("(/ 1 (if (< t 1.0) 0 1))"), Some(String::from("Not supported in a signal: integer division by a value that may be zero at 1:1")));
+        assert_eq!(message("(* 9223372036854775807 (if (< t 1.0) 1 2))"), Some(String::from("Not supported in a signal: integer arithmetic that may overflow at 1:1")));
+
+        // globals are read when the signal is compiled
+        let signal = compile("(* t f)").unwrap();
+        nm.define_native("f", Item::Float(220.0));
+        assert_eq!(signal.eval(&[2.0]), 880.0);
+
+        let kind = |s: &str| compile(s).err().map(|err| err.kind);
+        assert!(matches!(kind("(* t true)"), Some(ErrorKind::TypeMismatch(_))));
+        assert!(matches!(kind("(< t 1.0)"), Some(ErrorKind::TypeMismatch(_))));
+        assert!(matches!(kind("(if (< t 1.0) 1.0 true)"), Some(ErrorKind::TypeMismatch(_))));
+        assert!(matches!(kind("(progn (print t) t)"), Some(ErrorKind::Unsupported(_))));
+        assert!(matches!(kind("(square t t)"), Some(ErrorKind::Arity { .. })));
+        assert!(matches!(kind("(* t g)"), Some(ErrorKind::Unbound(_))));
+
+        let recursive = compile("(letrec (fac (func (x) (if (< x 1.0) 1.0 (* x (fac (- x 1.0)))))) (fac t))").err().unwrap();
+        assert_eq!(recursive.to_string(), "Not supported in a signal: calls nested more than 64 deep, is fac recursive? at 1:47");
+    }
+
+    #[test]
+    fn blocks() {
+        use std::sync::Arc;
+        use error::ErrorKind;
+        use interpreter::Interpreter;
+        use parser::parse_string;
+        use symbol::Symbol;
+
+        let nm = Interpreter::new();
+        nm.define_native("f", Item::Float(440.0));
+        let times = [0.0, 0.25, 0.5, 2.0, 3.5];
+        let block = |s: &str| {
+            let program = nm.resolve(&parse_string(String::from(s)).unwrap(), &["t"]).unwrap();
+            let mut out = [0.0; 5];
+            nm.eval_block(&program, &[("t", &times)], &mut out).map(|_| out)
+        };
+
+        // a block gives the same samples as evaluating once per sample
+        let programs = [
+            "(* (sin (* t f)) 0.5)",
+            "(if (< t 0.4) (* t 2.0) (- t 1.0))",
+            "(letrec (count (func (x n) (if (< x 1.0) n (count (- x 1.0) (+ n 1.0))))) (count t 0.0))",
+            "(if (or (== t 0.0) (not (< t 3.0))) 1.0 t)",
+            "(if (< 0.1 t 1.0 3.0) (- t) (+ t t 1))",
+            "(let (g (func (x) (* x t))) (if (< t 1.0) (g 2.0) 0.0))",
+            "(letrec (scale 3.0) (g (func (x) (if (< x t) (g (* x 2.0)) (* x scale)))) (h (func () (g 0.1))) (if (> t 0.3) (h) t))",
+            "2.5",
+        ];
+        for program in programs {
+            let samples = block(program).unwrap();
+            let parsed = nm.resolve(&parse_string(String::from(program)).unwrap(), &["t"]).unwrap();
+            for (time, sample) in times.iter().zip(samples) {
+                match nm.eval_with(&parsed, &[("t", Item::Float(*time))]) {
+                    Ok(Item::Float(expected)) => assert_eq!(sample, expected, "{} at {}", program, time),
+                    other => panic!("{} gave {:?}", program, other)
+                }
+            }
+        }
+
+        // integer branches are promoted like operands are
+        assert_eq!(block("(if (> t 0.5) 1 0)").unwrap(), [0.0, 0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(block("(if (> t 0.3) 2 0.5)").unwrap(), [0.5, 0.5, 2.0, 2.0, 2.0]);
+
+        let err = block("(< t 1.0)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::TypeMismatch(_)));
+        let err = block("(if (< t 1.0) 1.0 true)").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::TypeMismatch(_)));
+
+        // blocks are ordinary values to both evaluators
+        let env = default_env().push_frame(vec![(Symbol::intern("t"), Item::Floats(Arc::from(&times[..])))]);
+        let eval = |s: &str| format!("{:?}", eval_string(s, env.clone()).unwrap());
+        assert_eq!(eval("(* t 2.0)"), "#[0.0, 0.5, 1.0, 4.0, 7.0]");
+        assert_eq!(eval("(> t 1.0)"), "#[false, false, false, true, true]");
+        assert_eq!(eval("(if (> t 1.0) t 0.0)"), "#[0.0, 0.0, 0.0, 2.0, 3.5]");
+        assert_eq!(eval("(if (> t 1.0) 1 0)"), "#[0.0, 0.0, 0.0, 1.0, 1.0]");
+        let short = default_env().push_frame(vec![(Symbol::intern("t"), Item::Floats(Arc::from(&[1.0, 2.0][..])))]);
+        assert!(eval_string("(+ t (* 2.0 (if (> t 1.5) 1.0 2.0)))", short).is_ok());
+        assert_eq!(eval("(* t 2)"), "#[0.0, 0.5, 1.0, 4.0, 7.0]");
+        assert!(eval_string("(* t true)", env.clone()).is_err());
+    }
+
+    #[test]
+    fn optimizer() {
+        use interpreter::Interpreter;
+        use optimizer::dump;
+        use parser::parse_string;
+
+        let nm = Interpreter::new();
+        let optimized = |s: &str| dump(&nm.optimize(&parse_string(String::from(s)).unwrap(), &["t"]));
+
+        assert_eq!(optimized("(* 2.0 pi)"), "6.2831855");
+        assert_eq!(optimized("(letrec (x 2) (y (* x 3)) (+ x y))"), "8");
+        assert_eq!(optimized("(let (x 2) (let (x 3) (y (* x t)) (+ x y)))"), "(let (y (* 2 t)) (+ 3 y))");
+        assert_eq!(optimized("(let (f (* 440.0 2.0)) (sin (* t f)))"), "(sin (* t 880.0))");
+        assert_eq!(optimized("(if (< 1 2) (* t 2.0) (print t))"), "(* t 2.0)");
+        assert_eq!(optimized("(or (< t 1.0) (> 2 1) (print t))"), "(or (< t 1.0) true)");
+        assert_eq!(optimized("(and false (/ 1 0))"), "false");
+        assert_eq!(optimized("(letrec (fac (func (x) (if (<= x 1) 1 (* x (fac (- x 1)))))) (fac 9))"), "362880");
+        assert_eq!(optimized("(let (square (func (x) (* x x))) (+ (square 3.0) (square t)))"),
+            "(let (square (func (x) (* x x))) (+ 9.0 (square t)))");
+
+        // globals that are changed are left alone, as is anything that fails
+        assert_eq!(optimized("(define x 2) (set! x 3) (* x 2)"), "(define x 2)\n(set! x 3)\n(* x 2)");
+        assert_eq!(optimized("(+ 1 \"a\")"), "(+ 1 \"a\")");
+        assert_eq!(optimized("(let (x 0) (/ 1 x))"), "(/ 1 0)");
+        assert_eq!(optimized("(define (twice x) (* x pi))"), "(define (twice x) (* x pi))");
+
+        // constants are substituted into defaults too
+        assert_eq!(optimized("(letrec (x 2) (f (func (a &optional (y x)) (+ a y))) (f t))"),
+            "(letrec (f (func (a &optional (y 2)) (+ a y))) (f t))");
+        assert_eq!(optimized("(let (x 2) (define (h &optional (y x)) y))"), "(define (h &optional (y 2)) y)");
+
+        // the optimized program does what the original did
+        let programs = [
+            ("(letrec (fac (func (x) (if (<= x 1) 1 (* x (fac (- x 1)))))) (fac 12))", "479001600"),
+            ("(letrec (x 3) (y (func (a) (+ a x))) (z 4) (w (func () (y z))) (w))", "7"),
+            ("(letrec (even (func (n) (if (== n 0) true (odd (- n 1))))) (odd (func (n) (if (== n 0) false (even (- n 1))))) (even 7))", "false"),
+            ("(progn 1 (print \"side effect\") (if false 1 2))", "2"),
+            ("(letrec (x 2) (f (func (a &optional (y x)) (+ a y))) (f 1))", "3"),
+            ("(progn (let (x 2) (define (h &optional (y x)) y)) (h))", "2"),
+        ];
+        for (program, expected) in programs {
+            assert_eq!(format!("{:?}", eval_string(program, default_env()).unwrap()), expected, "{}", program);
+        }
+
+        // globals are not read into functions, which may run after they change
+        let env = default_env();
+        let run = |s: &str| format!("{:?}", eval_string(s, env.clone()).unwrap());
+        run("(define k 1)");
+        let define = optimizer::optimize(&parse_string(String::from("(define f (func (x) (* x k)))")).unwrap(), &env);
+        eval::eval(&resolver::resolve(&define, &env).unwrap(), &env).unwrap();
+        run("(set! k 5)");
+        assert_eq!(run("(f 1)"), "5");
+
+        // nor are globals that a function already defined can change
+        run("(define counter 0)");
+        run("(define (bump) (set! counter (+ counter 1)))");
+        assert_eq!(run("(progn (bump) counter)"), "1");
+    }
 }
@@ -103,7 +103,7 @@ impl fmt::Debug for List<Item> {
 impl fmt::Debug for Node<Item> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.car {
-            Item::List(list) => match &list.head { 
+            Item::List(list, _) => match &list.head { 
                 Some(node) => f.debug_struct("List").field("head", &node).finish(),
                 None => f.write_str(")")
             },
@@ -0,0 +1,384 @@
+use std::{cell::Cell, collections::HashSet, sync::Arc};
+
+use crate::{
+    program::{Item, Builtin, Env, Params},
+    list::List,
+    symbol::Symbol,
+    lexer::Span,
+    eval::{apply_operator, decides},
+    parser::list_from,
+    resolver::replace_head,
+    builtins::{func_parts, parse_params, let_frames, LetFrame}};
+
+/// How many calls deep the optimizer follows a function with known arguments
+/// before leaving the call to run time.
+const MAX_DEPTH: usize = 64;
+
+/// How many function bodies the optimizer evaluates ahead of time in one
+/// program, so that functions making several recursive calls cannot keep it
+/// busy for long.
+const MAX_CALLS: usize = 10_000;
+
+/// One of a run of adjacent `let`-bound functions.
+struct Member {
+    name: Symbol,
+    params: Params,
+    body: Item,
+}
+
+/// What the optimizer knows about a name.
+#[derive(Clone)]
+enum Known {
+    Constant(Item),
+    /// A `let`-bound function, with its run, the scope it was defined in and
+    /// whether the run can see itself, as it can in a `letrec`.
+    Function(Arc<Vec<Member>>, usize, Scope, bool),
+    /// Bound to something only known when the program runs.
+    Unknown,
+}
+
+/// Names bound around the code being optimized, innermost first.
+type Scope = List<(Symbol, Known)>;
+
+/// Simplifies a parsed, unresolved program without changing what it does:
+/// operators whose operands are constants are applied, `if`s with a constant
+/// condition are replaced by the branch they take, `let`-bound constants are
+/// substituted where they are used, and calls to `let`-bound functions whose
+/// arguments are all known are replaced by their result when it is constant.
+///
+/// Globals that neither the program nor any function already defined can
+/// `define` or `set!` are read once, here, and substituted too, except in
+/// function bodies, which may be called after the globals change. Anything
+/// that would fail is left for `eval` to report.
+pub fn optimize(program: &Item, env: &Env) -> Item {
+    let mut assigned = HashSet::new();
+    collect_assigned(program, &mut assigned);
+    for value in env.globals.read().unwrap().values() {
+        collect_assigned_by(value, &mut assigned);
+    }
+    let optimizer = Optimizer { env, assigned, calls: Cell::new(0) };
+    optimizer.item(program, &List::new(), true, 0)
+}
+
+/// Names given a value by `define` or `set!` anywhere in `item`.
+fn collect_assigned(item: &Item, assigned: &mut HashSet<Symbol>) {
+    if let Item::List(list, _) = item {
+        if let Some(Item::Builtin(Builtin::Define | Builtin::Set)) = list.car() {
+            match list.cdr().car() {
+                Some(Item::Identifier(name, _)) => { assigned.insert(*name); },
+                Some(Item::List(signature, _)) => if let Some(Item::Identifier(name, _)) = signature.car() {
+                    assigned.insert(*name);
+                },
+                _ => ()
+            }
+        }
+        for item in list.iter() {
+            collect_assigned(item, assigned);
+        }
+    }
+}
+
+/// Names that calling `value` could give a value with `define` or `set!`,
+/// if it is a function.
+fn collect_assigned_by(value: &Item, assigned: &mut HashSet<Symbol>) {
+    let Item::Function(closure) = value else {
+        return;
+    };
+    let group = closure.group.iter().flat_map(|group| group.members.iter());
+    for lambda in [&closure.lambda].into_iter().chain(group) {
+        collect_assigned(&lambda.body, assigned);
+        for (_, default) in &lambda.params.optional {
+            collect_assigned(default, assigned);
+        }
+        // functions it captured can be called from it too
+        for (_, captured) in lambda.env.iter().flatten() {
+            collect_assigned_by(captured, assigned);
+        }
+    }
+}
+
+fn is_constant(item: &Item) -> bool {
+    matches!(item, Item::Number(_) | Item::Float(_) | Item::Boolean(_) | Item::String(_) | Item::Nil)
+}
+
+/// Every name a parameter list binds.
+fn param_names(params: &Params) -> Vec<Symbol> {
+    let optional = params.optional.iter().map(|(name, _)| *name);
+    params.required.iter().copied().chain(optional).chain(params.rest).collect()
+}
+
+fn bind_unknown(scope: &Scope, names: Vec<Symbol>) -> Scope {
+    names.into_iter().fold(scope.clone(), |scope, name| scope.prepend((name, Known::Unknown)))
+}
+
+struct Optimizer<'a> {
+    env: &'a Env,
+    assigned: HashSet<Symbol>,
+    calls: Cell<usize>,
+}
+
+impl<'a> Optimizer<'a> {
+    fn lookup(&self, name: Symbol, scope: &Scope, globals: bool) -> Known {
+        if let Some((_, known)) = scope.iter().find(|(bound, _)| *bound == name) {
+            return known.clone();
+        }
+        if !globals || self.assigned.contains(&name) || self.env.is_local(name) {
+            return Known::Unknown;
+        }
+        match self.env.globals.read().unwrap().lookup(name) {
+            Some(value) if is_constant(value) => Known::Constant(value.clone()),
+            _ => Known::Unknown
+        }
+    }
+
+    /// Optimizes `item`. Globals are only substituted if `globals` is set, and
+    /// `depth` counts the function calls being evaluated ahead of time.
+    fn item(&self, item: &Item, scope: &Scope, globals: bool, depth: usize) -> Item {
+        match item {
+            Item::Identifier(name, _) => match self.lookup(*name, scope, globals) {
+                Known::Constant(value) => value,
+                _ => item.clone()
+            },
+            Item::List(list, span) => match list.car() {
+                Some(Item::Builtin(builtin)) => self.builtin(builtin, list, *span, scope, globals, depth).unwrap_or_else(|| item.clone()),
+                Some(Item::Operator(op)) => {
+                    let mut items = Vec::new();
+                    for item in list.iter() {
+                        items.push(self.item(item, scope, globals, depth));
+                        // `and` and `or` never evaluate what comes after an
+                        // operand that settles their result
+                        if items.last().is_some_and(|item| decides(op, item)) {
+                            break;
+                        }
+                    }
+                    let operands = &items[1..];
+                    if operands.iter().all(is_constant) {
+                        if let Ok(value) = apply_operator(op, operands, *span) {
+                            return value;
+                        }
+                    }
+                    Item::List(list_from(items), *span)
+                },
+                Some(head) => {
+                    let items = list.iter().map(|item| self.item(item, scope, globals, depth)).collect::<Vec<Item>>();
+                    self.call(head, &items[1..], scope, globals, depth).unwrap_or_else(|| Item::List(list_from(items), *span))
+                },
+                None => item.clone()
+            },
+            _ => item.clone()
+        }
+    }
+
+    /// The value of calling `head` with `args`, if `head` names a `let`-bound
+    /// function and the call comes out constant.
+    fn call(&self, head: &Item, args: &[Item], scope: &Scope, globals: bool, depth: usize) -> Option<Item> {
+        let Item::Identifier(name, _) = head else {
+            return None;
+        };
+        let Known::Function(members, index, defined_in, recursive) = self.lookup(*name, scope, globals) else {
+            return None;
+        };
+        let params = &members[index].params;
+        if depth == MAX_DEPTH || self.calls.get() == MAX_CALLS || !args.iter().all(is_constant) || params.rest.is_some() {
+            return None;
+        }
+        if args.len() < params.min() || params.max().is_some_and(|max| args.len() > max) {
+            return None;
+        }
+        self.calls.set(self.calls.get() + 1);
+
+        let mut args = args.iter();
+        let run = if recursive { bind_run(&defined_in, &members) } else { defined_in };
+        let mut call_scope = params.required.iter().zip(&mut args).fold(run, |scope, (name, arg)| scope.prepend((*name, Known::Constant(arg.clone()))));
+        for (name, default) in params.optional.iter() {
+            let value = match args.next() {
+                Some(arg) => arg.clone(),
+                None => self.item(default, &call_scope, globals, depth + 1)
+            };
+            if !is_constant(&value) {
+                return None;
+            }
+            call_scope = call_scope.prepend((*name, Known::Constant(value)));
+        }
+        let value = self.item(&members[index].body, &call_scope, globals, depth + 1);
+        is_constant(&value).then_some(value)
+    }
+
+    /// `(func (params) body)` with its defaults and body optimized. Globals
+    /// are left alone, as the function may be called after they change.
+    fn func(&self, list: &List<Item>, span: Span, scope: &Scope, depth: usize) -> Option<Item> {
+        let (params, params_span, body) = func_parts(&Item::List(list.clone(), span))?;
+        let names = param_names(&parse_params(&params, params_span).ok()?);
+        let params = self.params(&params, scope, false, depth);
+        let body = self.item(&body, &bind_unknown(scope, names), false, depth);
+        Some(Item::List(replace_head(vec![Item::Builtin(Builtin::Func), Item::List(params, params_span), body], list, 3), span))
+    }
+
+    /// A parameter list with its defaults optimized, each seeing the
+    /// parameters before it.
+    fn params(&self, params: &List<Item>, scope: &Scope, globals: bool, depth: usize) -> List<Item> {
+        let mut scope = scope.clone();
+        let mut optimized = Vec::new();
+        for param in params.iter() {
+            match param {
+                Item::Identifier(name, _) => {
+                    if !name.as_str().starts_with('&') {
+                        scope = scope.prepend((*name, Known::Unknown));
+                    }
+                    optimized.push(param.clone());
+                },
+                Item::List(default, span) => {
+                    let (Some(name @ Item::Identifier(symbol, _)), Some(value)) = (default.car(), default.cdr().car().cloned()) else {
+                        optimized.push(param.clone());
+                        continue;
+                    };
+                    let value = self.item(&value, &scope, globals, depth);
+                    scope = scope.prepend((*symbol, Known::Unknown));
+                    optimized.push(Item::List(replace_head(vec![name.clone(), value], default, 2), *span));
+                },
+                other => optimized.push(other.clone())
+            }
+        }
+        list_from(optimized)
+    }
+
+    /// An optimized builtin form, or `None` to leave it as it is.
+    fn builtin(&self, builtin: &Builtin, list: &List<Item>, span: Span, scope: &Scope, globals: bool, depth: usize) -> Option<Item> {
+        let args = list.cdr().iter().cloned().collect::<Vec<Item>>();
+        let rebuilt = |items: Vec<Item>| Some(Item::List(list_from([Item::Builtin(builtin.clone())].into_iter().chain(items).collect()), span));
+        match (builtin, args.as_slice()) {
+            (Builtin::Func, _) => self.func(list, span, scope, depth),
+            (Builtin::Let | Builtin::Letrec, _) => self.let_form(builtin, &args, span, scope, globals, depth),
+            (Builtin::If, [condition, branches @ ..]) => {
+                let condition = self.item(condition, scope, globals, depth);
+                let taken = match condition {
+                    Item::Boolean(true) => branches.first(),
+                    Item::Boolean(false) => branches.get(1),
+                    _ => None
+                };
+                if let Some(branch) = taken {
+                    return Some(self.item(branch, scope, globals, depth));
+                }
+                let branches = branches.iter().map(|item| self.item(item, scope, globals, depth));
+                rebuilt([condition].into_iter().chain(branches).collect())
+            },
+            (Builtin::Define, [Item::List(signature, signature_span), body, ..]) => {
+                let params = parse_params(&signature.cdr(), *signature_span).ok()?;
+                let signature = self.params(&signature.cdr(), scope, false, depth).prepend(signature.car()?.clone());
+                let body = self.item(body, &bind_unknown(scope, param_names(&params)), false, depth);
+                Some(Item::List(replace_head(vec![Item::Builtin(Builtin::Define), Item::List(signature, *signature_span), body], list, 3), span))
+            },
+            (Builtin::Define | Builtin::Set, [name @ Item::Identifier(_, _), value, ..]) => {
+                let value = self.item(value, scope, globals, depth);
+                Some(Item::List(replace_head(vec![Item::Builtin(builtin.clone()), name.clone(), value], list, 3), span))
+            },
+            (Builtin::Define | Builtin::Set, _) => None,
+            (Builtin::Progn, [forms @ .., last]) => {
+                // constants before the last form do nothing
+                let forms = forms.iter().map(|form| self.item(form, scope, globals, depth)).filter(|form| !is_constant(form));
+                let last = self.item(last, scope, globals, depth);
+                rebuilt(forms.chain([last]).collect())
+            },
+            _ => rebuilt(args.iter().map(|item| self.item(item, scope, globals, depth)).collect())
+        }
+    }
+
+    /// Optimizes a `let`. Bindings whose value turns out constant are dropped
+    /// once the value has been substituted into the rest of the `let`, and a
+    /// `let` left with no bindings is replaced by its body.
+    fn let_form(&self, builtin: &Builtin, args: &[Item], span: Span, scope: &Scope, globals: bool, depth: usize) -> Option<Item> {
+        let (frames, body) = let_frames(builtin, args, span).ok()?;
+        let mut scope = scope.clone();
+        // the bindings kept, and whether each binds a function
+        let mut kept: Vec<(Item, bool)> = Vec::new();
+        let mut dropped = None;
+        for frame in frames {
+            match frame {
+                LetFrame::Values(bindings) => {
+                    // every value sees only the frames before this one
+                    let outer = scope.clone();
+                    for binding in bindings {
+                        let value = self.item(&binding.value, &outer, globals, depth);
+                        let rebuilt = binding.with_value(value.clone());
+                        if is_constant(&value) && !self.assigned.contains(&binding.name) {
+                            scope = scope.prepend((binding.name, Known::Constant(value)));
+                            dropped = Some(rebuilt);
+                        }
+                        else if let Some(member) = member(binding.name, &value).filter(|_| !self.assigned.contains(&binding.name)) {
+                            // a function bound outside a `letrec` cannot call itself
+                            let known = Known::Function(Arc::new(vec![member]), 0, outer.clone(), false);
+                            scope = scope.prepend((binding.name, known));
+                            kept.push((rebuilt, true));
+                            dropped = None;
+                        }
+                        else {
+                            scope = scope.prepend((binding.name, Known::Unknown));
+                            kept.push((rebuilt, false));
+                            dropped = None;
+                        }
+                    }
+                },
+                LetFrame::Group(run) => {
+                    if let (Some(dropped), Some((_, true))) = (dropped.take(), kept.last()) {
+                        // leaving it out would join two runs of functions into one
+                        kept.push((dropped, false));
+                    }
+                    let members = run.iter().map(|function| Member { name: function.binding.name, params: function.params.clone(), body: function.body.clone() }).collect();
+                    scope = bind_run(&scope, &Arc::new(members));
+                    for function in run {
+                        let Item::List(func, func_span) = &function.binding.value else {
+                            return None;
+                        };
+                        let func = self.func(func, *func_span, &scope, depth)?;
+                        kept.push((function.binding.with_value(func), true));
+                    }
+                }
+            }
+        }
+
+        let body = self.item(&body, &scope, globals, depth);
+        // functions that are never called after the body has been folded
+        // away cannot do anything
+        if kept.iter().all(|(_, function)| *function) && (kept.is_empty() || is_constant(&body)) {
+            return Some(body);
+        }
+        let bindings = kept.into_iter().map(|(binding, _)| binding);
+        Some(Item::List(list_from([Item::Builtin(builtin.clone())].into_iter().chain(bindings).chain([body]).collect()), span))
+    }
+}
+
+/// `value` as a function named `name`, if it is a `func` form.
+fn member(name: Symbol, value: &Item) -> Option<Member> {
+    let (params, params_span, body) = func_parts(value)?;
+    let params = parse_params(&params, params_span).ok()?;
+    Some(Member { name, params, body })
+}
+
+/// `scope` with every function of a run bound, each knowing the whole run.
+fn bind_run(scope: &Scope, members: &Arc<Vec<Member>>) -> Scope {
+    (0..members.len()).fold(scope.clone(), |run, index| {
+        run.prepend((members[index].name, Known::Function(members.clone(), index, scope.clone(), true)))
+    })
+}
+
+/// Writes `program` back out as nm source, one top-level form per line.
+pub fn dump(program: &Item) -> String {
+    match program {
+        Item::List(list, _) if matches!(list.car(), Some(Item::Builtin(Builtin::Progn))) => {
+            list.cdr().iter().map(source).collect::<Vec<String>>().join("\n")
+        },
+        other => source(other)
+    }
+}
+
+/// `item` as nm source.
+pub fn source(item: &Item) -> String {
+    let items = |list: &List<Item>| list.iter().map(source).collect::<Vec<String>>().join(" ");
+    match item {
+        Item::List(list, _) => format!("({})", items(list)),
+        Item::ListLiteral(list) => format!("'({})", items(list)),
+        Item::Builtin(builtin) => builtin.to_string(),
+        Item::Operator(op) => op.to_string(),
+        other => format!("{:?}", other)
+    }
+}
@@ -1,83 +1,221 @@
-use std::slice::Iter;
+use std::{collections::HashSet, iter::Peekable, slice::Iter};
 
-use crate::lexer::lex;
+use crate::lexer::{lex_recovering, Token, TokenKind, Span};
+use crate::error::NmError;
+use crate::symbol::Symbol;
 use crate::program::*;
 use crate::list::*;
 
-fn parse_token(token: &String) -> Result<Item, String> {
-    if token.contains('.') {
-        match token.parse::<f32>() {
-            Ok(num) => return Ok(Item::Float(num)),
-            _ => (),
+/// Decodes the escapes in a string literal token, dropping the surrounding quotes.
+fn unescape(token: &Token) -> Result<String, NmError> {
+    let body = &token.text[1..token.text.len() - 1];
+    let mut out = String::with_capacity(body.len());
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => out.push('"'),
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('u') => {
+                let decoded = chars.as_str().strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .and_then(|(hex, tail)| u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).map(|c| (c, tail)));
+                match decoded {
+                    Some((c, tail)) => {
+                        out.push(c);
+                        chars = tail.chars();
+                    },
+                    None => return Err(NmError::parse(String::from("invalid unicode escape in string"), token.span))
+                }
+            },
+            Some(other) => return Err(NmError::parse(format!("unknown escape \\{} in string", other), token.span)),
+            None => return Err(NmError::parse(String::from("unfinished escape in string"), token.span)),
         }
     }
-    match token.parse::<i32>() {
-        Ok(num) => return Ok(Item::Number(num)),
+    Ok(out)
+}
+
+fn parse_token(token: &Token) -> Result<Item, NmError> {
+    let text = &token.text;
+    match token.kind {
+        TokenKind::Float => {
+            return text.parse::<f32>()
+                .map(Item::Float)
+                .map_err(|_| NmError::parse(format!("invalid float literal {}", text), token.span));
+        },
+        TokenKind::Number => {
+            return text.parse::<i64>()
+                .map(Item::Number)
+                .map_err(|_| NmError::parse(format!("integer literal {} out of range", text), token.span));
+        },
         _ => ()
     }
 
-    if token == "true" {
-        return Ok(Item::Boolean(true));
+    if text == "true" {
+        Ok(Item::Boolean(true))
     }
-    else if token == "false" {
-        return Ok(Item::Boolean(false))
+    else if text == "false" {
+        Ok(Item::Boolean(false))
     }
-    else if let(Some(op)) = get_operator(token) {
-        return Ok(Item::Operator(op));
+    else if let Some(op) = get_operator(text) {
+        Ok(Item::Operator(op))
     }
-    else if let(Some(built)) = get_builtin(token) {
-        return Ok(Item::Builtin(built));
+    else if let Some(built) = get_builtin(text) {
+        Ok(Item::Builtin(built))
     }
-    else if token.ends_with("\"") && token.starts_with("\"") {
-        return Ok(Item::String(String::from(&token[1..token.len()- 1])));
+    else if token.kind == TokenKind::String {
+        Ok(Item::String(unescape(token)?))
     }
-    else if token == "nil" {
-        return Ok(Item::Nil)
+    else if text == "nil" {
+        Ok(Item::Nil)
     }
     else {
-        return Ok(Item::Identifier(token.clone()));
+        Ok(Item::Identifier(Symbol::intern(text), token.span))
     }
 }
 
-pub fn parse_helper(tokens: &mut Iter<String>) -> Result<Item, String> {
-    let mut list = List::new();
+pub(crate) fn list_from(items: Vec<Item>) -> List<Item> {
+    items.into_iter().rev().fold(List::new(), |list, item| list.prepend(item))
+}
 
-    while let Some(token) = tokens.next() {
-        if token == ")" {
-            match parse_helper(tokens) {
-                Ok(new_list ) => {
-                    list = list.prepend(new_list);
-                },
-                Err(msg) => return Err(msg)
-            }
-        }
-        else if token == "'(" {
-            return Ok(Item::ListLiteral(list))
+/// The result of parsing in recovering mode: the top-level forms that parsed
+/// cleanly, wrapped in a `progn`, and every problem found along the way.
+pub struct ParseResult {
+    pub program: Item,
+    pub diagnostics: Vec<NmError>,
+}
+
+struct Parser<'a> {
+    tokens: Peekable<Iter<'a, Token>>,
+    diagnostics: Vec<NmError>,
+    /// The offsets of the open parens that nothing closes. A list opened by
+    /// one of these ends at the next `(` at the start of a line, which is
+    /// taken as the start of the next top-level form.
+    unclosed: HashSet<usize>,
+    /// Set once an unclosed list has been reported, so unclosed lists around
+    /// it give up quietly instead of reporting themselves as well.
+    abandoned: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn atom(&mut self, token: &Token) -> Option<Item> {
+        if let Some(err) = &token.error {
+            self.diagnostics.push(err.clone().into());
+            return None;
         }
-        else if token == "(" {
-            return Ok(Item::List(list))
+        parse_token(token).map_err(|err| self.diagnostics.push(err)).ok()
+    }
+
+    /// Parses the contents of a list whose opening paren, `open`, has already
+    /// been consumed, up to and including the matching close paren. Returns
+    /// `None` if anything inside was malformed.
+    fn list(&mut self, open: &Token) -> Option<Item> {
+        let mut items = Some(Vec::new());
+
+        loop {
+            let token = match self.tokens.peek() {
+                Some(token) if self.unclosed.contains(&open.span.offset) && token.kind == TokenKind::OpenParen && token.span.column == 1 => None,
+                next => next.copied()
+            };
+            let token = match token {
+                Some(token) => token,
+                None => {
+                    if !self.abandoned {
+                        self.diagnostics.push(NmError::parse(format!("unmatched {} is never closed", open.text), open.span));
+                        self.abandoned = true;
+                    }
+                    return None;
+                }
+            };
+            self.tokens.next();
+
+            let item = match token.kind {
+                TokenKind::CloseParen => {
+                    return items.map(|items| if open.text == "'(" {
+                        Item::ListLiteral(list_from(items))
+                    }
+                    else {
+                        Item::List(list_from(items), open.span)
+                    });
+                },
+                TokenKind::OpenParen => {
+                    let item = self.list(token);
+                    if !self.unclosed.contains(&open.span.offset) {
+                        // this list is closed, so it carries on past an
+                        // unclosed one inside it
+                        self.abandoned = false;
+                    }
+                    item
+                },
+                _ => self.atom(token)
+            };
+            items = items.zip(item).map(|(mut items, item)| {
+                items.push(item);
+                items
+            });
         }
-        else {
-            match parse_token(token) {
-                Ok(item) => {
-                    list = list.prepend(item);
+    }
+
+    fn program(&mut self) -> Item {
+        let mut items = vec![Item::Builtin(Builtin::Progn)];
+
+        while let Some(token) = self.tokens.next() {
+            let item = match token.kind {
+                TokenKind::CloseParen => {
+                    self.diagnostics.push(NmError::parse(String::from("unmatched ) has no opening paren"), token.span));
+                    None
+                },
+                TokenKind::OpenParen => {
+                    self.abandoned = false;
+                    self.list(token)
                 },
-                Err(msg) => return Err(msg)
-            }
+                _ => self.atom(token)
+            };
+            items.extend(item);
         }
+        Item::List(list_from(items), Span::default())
     }
-    list = list.prepend(Item::Builtin(Builtin::Progn));
-    Ok(Item::List(list))
 }
 
-pub fn parse(mut tokens: Vec<String>) -> Result<Item, String> {
-    tokens.reverse();
-    let mut iter = tokens.iter();
-    //iter.next();
-    parse_helper(&mut iter)
+/// Parses as much of `tokens` as possible instead of stopping at the first
+/// error, for tools that want to report every broken form at once. Forms with
+/// errors in them, including the `Error` tokens from
+/// [`lex_recovering`], are left out of the
+/// returned program.
+pub fn parse_recovering(tokens: &[Token]) -> ParseResult {
+    let mut open = Vec::new();
+    for token in tokens {
+        match token.kind {
+            TokenKind::OpenParen => open.push(token.span.offset),
+            TokenKind::CloseParen => { open.pop(); },
+            _ => ()
+        }
+    }
+    let mut parser = Parser {
+        tokens: tokens.iter().peekable(),
+        diagnostics: Vec::new(),
+        unclosed: open.into_iter().collect(),
+        abandoned: false,
+    };
+    let program = parser.program();
+    ParseResult { program, diagnostics: parser.diagnostics }
+}
+
+pub fn parse(tokens: Vec<Token>) -> Result<Item, NmError> {
+    let ParseResult { program, diagnostics } = parse_recovering(&tokens);
+    match diagnostics.into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(program)
+    }
 }
 
-pub fn parse_string(s: String) -> Result<Item, String> {
-    let mut tokens = lex(&s);
-    parse(tokens)
-}
\ No newline at end of file
+pub fn parse_string(s: String) -> Result<Item, NmError> {
+    parse(lex_recovering(&s))
+}
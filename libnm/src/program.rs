@@ -1,6 +1,6 @@
-use std::fmt;
+use std::{collections::HashMap, fmt, sync::{Arc, RwLock}};
 
-use crate::list::List;
+use crate::{list::List, lexer::Span, error::NmError, symbol::Symbol, compiler::BodyCode};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BinaryOperator {
@@ -30,14 +30,40 @@ pub enum Operator {
     BooleanOperator(BooleanOperator),
 }
 
-pub fn get_operator(s: &String) -> Option<Operator> {
-    match s.as_str() {
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Operator::BinaryOperator(BinaryOperator::Mul) => "*",
+            Operator::BinaryOperator(BinaryOperator::Div) => "/",
+            Operator::BinaryOperator(BinaryOperator::Add) => "+",
+            Operator::BinaryOperator(BinaryOperator::Sub) => "-",
+            Operator::BinaryComparator(BinaryComparator::Eq) => "==",
+            Operator::BinaryComparator(BinaryComparator::Neq) => "!=",
+            Operator::BinaryComparator(BinaryComparator::Lt) => "<",
+            Operator::BinaryComparator(BinaryComparator::Gt) => ">",
+            Operator::BinaryComparator(BinaryComparator::Lte) => "<=",
+            Operator::BinaryComparator(BinaryComparator::Gte) => ">=",
+            Operator::UnaryOperator(UnaryOperator::Exp) => "exp",
+            Operator::UnaryOperator(UnaryOperator::Log) => "log",
+            Operator::UnaryOperator(UnaryOperator::Sin) => "sin",
+            Operator::UnaryOperator(UnaryOperator::Rec) => "rec",
+            Operator::BooleanOperator(BooleanOperator::Or) => "or",
+            Operator::BooleanOperator(BooleanOperator::And) => "and",
+            Operator::BooleanOperator(BooleanOperator::Not) => "not",
+        };
+        f.write_str(name)
+    }
+}
+
+pub fn get_operator(s: &str) -> Option<Operator> {
+    match s {
         "*"   => Some(Operator::BinaryOperator(BinaryOperator::Mul)),
         "+"   => Some(Operator::BinaryOperator(BinaryOperator::Add)),
         "/"   => Some(Operator::BinaryOperator(BinaryOperator::Div)),
         "-"   => Some(Operator::BinaryOperator(BinaryOperator::Sub)),
 
         "^"   => Some(Operator::UnaryOperator(UnaryOperator::Exp)),
+        "exp" => Some(Operator::UnaryOperator(UnaryOperator::Exp)),
         "log" => Some(Operator::UnaryOperator(UnaryOperator::Log)),
         "ln"  => Some(Operator::UnaryOperator(UnaryOperator::Log)),
         "sin" => Some(Operator::UnaryOperator(UnaryOperator::Sin)),
@@ -59,11 +85,6 @@ pub fn get_operator(s: &String) -> Option<Operator> {
         "or" => Some(Operator::BooleanOperator(BooleanOperator::Or)),
         "and" => Some(Operator::BooleanOperator(BooleanOperator::And)),
         "not" => Some(Operator::BooleanOperator(BooleanOperator::Not)),
-
-        "sin" => Some(Operator::UnaryOperator(UnaryOperator::Sin)),
-        "log" => Some(Operator::UnaryOperator(UnaryOperator::Log)),
-        "rec" => Some(Operator::UnaryOperator(UnaryOperator::Rec)),
-        "exp" => Some(Operator::UnaryOperator(UnaryOperator::Exp)),
         _ => None
     }
 }
@@ -74,59 +95,319 @@ pub enum Builtin {
     Progn,
     Print,
     Let,
+    Letrec,
     If,
     Input,
     Cat,
+    Error,
+    Define,
+    Set,
 }
 
-pub fn get_builtin(s: &String) -> Option<Builtin> {
-    match s.as_str() {
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Builtin::Func => "func",
+            Builtin::Progn => "progn",
+            Builtin::Print => "print",
+            Builtin::Let => "let",
+            Builtin::Letrec => "letrec",
+            Builtin::If => "if",
+            Builtin::Input => "input",
+            Builtin::Cat => "cat",
+            Builtin::Error => "error",
+            Builtin::Define => "define",
+            Builtin::Set => "set!",
+        };
+        f.write_str(name)
+    }
+}
+
+pub fn get_builtin(s: &str) -> Option<Builtin> {
+    match s {
         "func"  => Some(Builtin::Func),
         "progn" => Some(Builtin::Progn),
         "print" => Some(Builtin::Print),
         "let"   => Some(Builtin::Let),
+        "letrec" => Some(Builtin::Letrec),
         "if"    => Some(Builtin::If),
         "input" => Some(Builtin::Input),
         "cat"   => Some(Builtin::Cat),
+        "error" => Some(Builtin::Error),
+        "define" => Some(Builtin::Define),
+        "set!"  => Some(Builtin::Set),
         _ => None
     }
 }
 
+/// The bindings made together by one scope: a call's parameters, a single
+/// `let` binding or a group of adjacent `let`-bound functions.
+pub type Frame = Vec<(Symbol, Item)>;
+
+/// Local scopes, innermost first.
+pub type Bindings = List<Frame>;
+
+/// Where the resolver found a local: `depth` frames out from the innermost
+/// one, at `index` within that frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub depth: usize,
+    pub index: usize,
+}
+
+/// Definitions made with `define`. Each name gets a slot the first time it
+/// is seen, so resolved code can reach it without hashing. A slot holds
+/// `None` until its `define` has run.
+#[derive(Clone, Default)]
+pub struct GlobalTable {
+    slots: HashMap<Symbol, usize>,
+    values: Vec<Option<Item>>,
+}
+
+impl GlobalTable {
+    /// The slot for `name`, allocating one if it has none yet.
+    pub fn slot(&mut self, name: Symbol) -> usize {
+        let next = self.values.len();
+        let slot = *self.slots.entry(name).or_insert(next);
+        if slot == next {
+            self.values.push(None);
+        }
+        slot
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&Item> {
+        self.values.get(slot).and_then(|value| value.as_ref())
+    }
+
+    pub fn lookup(&self, name: Symbol) -> Option<&Item> {
+        self.slots.get(&name).and_then(|slot| self.get(*slot))
+    }
+
+    /// Every value that has been defined.
+    pub fn values(&self) -> impl Iterator<Item = &Item> {
+        self.values.iter().flatten()
+    }
+}
+
+pub type Globals = Arc<RwLock<GlobalTable>>;
+
+/// Bindings visible to an expression: the locals first, then the globals.
+/// Cloning an `Env` shares its globals, so a host can keep one around and
+/// see definitions from earlier programs in later ones.
+#[derive(Clone)]
+pub struct Env {
+    pub locals: Bindings,
+    pub globals: Globals,
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Env { locals: List::new(), globals: Arc::new(RwLock::new(GlobalTable::default())) }
+    }
+}
+
+impl Env {
+    pub fn push_frame(&self, frame: Frame) -> Env {
+        self.with_locals(self.locals.prepend(frame))
+    }
+
+    /// An environment with the same globals as this one but other locals.
+    pub fn with_locals(&self, locals: Bindings) -> Env {
+        Env { locals, globals: self.globals.clone() }
+    }
+
+    /// Finds `name` by searching the frames and then the globals, for code
+    /// that has not been through the resolver.
+    pub fn lookup(&self, name: Symbol) -> Option<Item> {
+        match self.locals.iter().flat_map(|frame| frame.iter().rev()).find(|(local, _)| *local == name) {
+            Some((_, item)) => Some(item.clone()),
+            None => self.globals.read().unwrap().lookup(name).cloned()
+        }
+    }
+
+    pub fn local(&self, address: Address) -> Option<Item> {
+        self.locals.iter().nth(address.depth).and_then(|frame| frame.get(address.index)).map(|(_, item)| item.clone())
+    }
+
+    pub fn global(&self, slot: usize) -> Option<Item> {
+        self.globals.read().unwrap().get(slot).cloned()
+    }
+
+    pub fn is_local(&self, name: Symbol) -> bool {
+        self.locals.iter().any(|frame| frame.iter().any(|(local, _)| *local == name))
+    }
+
+    pub fn define(&self, name: Symbol, value: Item) {
+        let mut globals = self.globals.write().unwrap();
+        let slot = globals.slot(name);
+        globals.values[slot] = Some(value);
+    }
+
+    /// Changes an existing global. Returns false if `name` was never defined.
+    pub fn set(&self, name: Symbol, value: Item) -> bool {
+        let mut globals = self.globals.write().unwrap();
+        match globals.slots.get(&name).copied() {
+            Some(slot) if globals.values[slot].is_some() => {
+                globals.values[slot] = Some(value);
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+/// The parameter list of a function: the required names, then the names
+/// after `&optional` with their default expressions, then the `&rest` name
+/// that collects any remaining arguments into a list.
+#[derive(Clone)]
+pub struct Params {
+    pub required: Vec<Symbol>,
+    pub optional: Vec<(Symbol, Item)>,
+    pub rest: Option<Symbol>,
+}
+
+impl Params {
+    pub fn min(&self) -> usize {
+        self.required.len()
+    }
+
+    /// The most arguments a call may pass, or `None` with a `&rest` parameter.
+    pub fn max(&self) -> Option<usize> {
+        match self.rest {
+            Some(_) => None,
+            None => Some(self.required.len() + self.optional.len())
+        }
+    }
+}
+
+/// A function created by `func`, together with the local bindings it was
+/// created in. Globals are not captured; they are looked up in the caller's
+/// environment. `name` is filled in when the function is bound by `let` or
+/// `define`, so backtraces can say which function was running. `code` is the
+/// body compiled for the VM, filled in the first time the VM calls it.
+pub struct Lambda {
+    pub name: Option<Symbol>,
+    pub params: Params,
+    pub body: Item,
+    pub env: Bindings,
+    pub code: BodyCode,
+}
+
+/// Functions bound next to each other in one `let`. Each of them can call
+/// itself and the others, so their bindings are added back to the captured
+/// environment whenever one of them is called.
+pub struct RecGroup {
+    pub members: Vec<Arc<Lambda>>,
+}
+
+#[derive(Clone)]
+pub struct Closure {
+    pub lambda: Arc<Lambda>,
+    pub group: Option<Arc<RecGroup>>,
+}
+
+/// The signature of a function the host exposes to nm code.
+pub type NativeImpl = dyn Fn(&[Item]) -> Result<Item, NmError> + Send + Sync;
+
+/// A function implemented in Rust. Calls are checked against `min` and `max`
+/// before `func` runs, so it can index its arguments freely.
+pub struct NativeFn {
+    pub name: String,
+    pub min: usize,
+    pub max: Option<usize>,
+    pub func: Box<NativeImpl>,
+}
+
+impl NativeFn {
+    pub fn new<F>(name: &str, min: usize, max: Option<usize>, func: F) -> Self
+    where F: Fn(&[Item]) -> Result<Item, NmError> + Send + Sync + 'static {
+        NativeFn { name: name.to_string(), min, max, func: Box::new(func) }
+    }
+}
+
 #[derive(Clone)]
 pub enum Item {
-    List(List<Item>),
+    List(List<Item>, Span),
     ListLiteral(List<Item>),
-    Identifier(String),
+    Identifier(Symbol, Span),
+    /// An identifier the resolver found in an enclosing scope.
+    Local(Symbol, Address, Span),
+    /// An identifier the resolver found in the global table.
+    Global(Symbol, usize, Span),
     Builtin(Builtin),
-    Function(List<Item>, Box<Item>),
+    Function(Closure),
+    NativeFn(Arc<NativeFn>),
     FunCall(String, List<Item>),
 
     Operator(Operator),
-    Number(i32),
+    Number(i64),
     Float(f32),
     String(String),
     Boolean(bool),
+    /// One float per sample of a block. See [`crate::block`].
+    Floats(Arc<[f32]>),
+    /// One boolean per sample, from comparing blocks.
+    Bools(Arc<[bool]>),
     Nil,
 }
 
+/// Quotes `s` the way it would be written in nm source, so the result reads
+/// back as the same string.
+pub fn escape_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Debug for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = self.required.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+        if !self.optional.is_empty() {
+            words.push(String::from("&optional"));
+            words.extend(self.optional.iter().map(|(name, default)| format!("({} {:?})", name, default)));
+        }
+        if let Some(rest) = &self.rest {
+            words.push(String::from("&rest"));
+            words.push(rest.to_string());
+        }
+        write!(f, "{})", words.join(" "))
+    }
+}
+
 impl fmt::Debug for Item {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Item::List(list) => {
+            Item::List(list, _) => {
                 f.write_str(format!("({:?}", list).as_str())
             },
             Item::ListLiteral(list) => {
                 f.write_str(format!("'({:?}", list).as_str())
             },
-            Item::Identifier(s) => f.write_str(s.as_str()),
+            Item::Identifier(s, _) | Item::Local(s, _, _) | Item::Global(s, _, _) => f.write_str(s.as_str()),
             Item::Builtin(s) => f.write_str(format!("{:?}", s).as_str()),
-            Item::Function(list, item) => f.write_str(format!("func({:?} {:?})", list, item).as_str()),
+            Item::Function(closure) => f.write_str(format!("func({:?} {:?})", closure.lambda.params, closure.lambda.body).as_str()),
+            Item::NativeFn(native) => f.write_str(format!("native({})", native.name).as_str()),
             Item::FunCall(args, ident) => f.write_str(format!("funcall({:?}, {:?})", args, ident).as_str()),
             Item::Operator(op) => f.write_str(format!("{:?}", op).as_str()),
             Item::Number(i) => f.write_str(format!("{:?}", i).as_str()),
             Item::Float(num) => f.write_str(format!("{:?}", num).as_str()),
-            Item::String(s) => f.write_str(format!("\"{:?}\"", s).as_str()),
+            Item::String(s) => f.write_str(escape_string(s).as_str()),
             Item::Boolean(b) => f.write_str(format!("{:?}", b).as_str()),
+            Item::Floats(samples) => write!(f, "#{:?}", samples),
+            Item::Bools(samples) => write!(f, "#{:?}", samples),
             Item::Nil => f.write_str("nil"),
         }
     }
@@ -0,0 +1,228 @@
+use std::{cell::Cell, collections::HashSet};
+
+use crate::{
+    program::{Item, Builtin, Env, Address},
+    list::List,
+    symbol::Symbol,
+    lexer::Span,
+    error::NmError,
+    parser::list_from,
+    builtins::{parse_params, let_frames, LetFrame}};
+
+/// The names bound by each frame `eval` will have built at some point in the
+/// program, innermost first, in the same order as the frames themselves.
+type Scope = List<Vec<Symbol>>;
+
+struct Resolver<'a> {
+    env: &'a Env,
+    /// Names given a value by a `define` somewhere in the program, which may
+    /// be used before the `define` itself is reached.
+    defined: HashSet<Symbol>,
+    /// How many function bodies the item being resolved is inside.
+    functions: Cell<usize>,
+    /// Whether the item being resolved is part of a `define`.
+    defining: Cell<bool>,
+}
+
+/// Rewrites the identifiers in `program` into the places `eval` will find
+/// them: `Item::Local` for names bound by an enclosing `func` or `let` (or by
+/// the frames already in `env`) and `Item::Global` for names that are or will
+/// be `define`d. Any other name is reported as unbound before the program
+/// runs, unless it is in the body of a `define`d function: those may be
+/// called after a later program has defined it, so it gets a global slot
+/// that is checked when it is read. Malformed special forms are left as they
+/// are for `eval` to report.
+pub fn resolve(program: &Item, env: &Env) -> Result<Item, NmError> {
+    let mut defined = HashSet::new();
+    collect_defines(program, &mut defined);
+
+    let frames = env.locals.iter().collect::<Vec<_>>();
+    let scope = frames.into_iter().rev().fold(List::new(), |scope: Scope, frame| {
+        scope.prepend(frame.iter().map(|(name, _)| *name).collect())
+    });
+    Resolver { env, defined, functions: Cell::new(0), defining: Cell::new(false) }.item(program, &scope)
+}
+
+fn collect_defines(item: &Item, defined: &mut HashSet<Symbol>) {
+    if let Item::List(list, _) = item {
+        if let Some(Item::Builtin(Builtin::Define)) = list.car() {
+            match list.cdr().car() {
+                Some(Item::Identifier(name, _)) => { defined.insert(*name); },
+                Some(Item::List(signature, _)) => if let Some(Item::Identifier(name, _)) = signature.car() {
+                    defined.insert(*name);
+                },
+                _ => ()
+            }
+        }
+        for item in list.iter() {
+            collect_defines(item, defined);
+        }
+    }
+}
+
+/// `list` with its first `count` items replaced by `head`.
+pub(crate) fn replace_head(head: Vec<Item>, list: &List<Item>, count: usize) -> List<Item> {
+    let tail = (0..count).fold(list.clone(), |list, _| list.cdr());
+    head.into_iter().rev().fold(tail, |list, item| list.prepend(item))
+}
+
+impl<'a> Resolver<'a> {
+    fn item(&self, item: &Item, scope: &Scope) -> Result<Item, NmError> {
+        match item {
+            Item::Identifier(name, span) => self.identifier(*name, *span, scope),
+            Item::List(list, span) => {
+                let resolved = match list.car() {
+                    Some(Item::Builtin(Builtin::Func)) => self.func(list, scope)?,
+                    Some(Item::Builtin(builtin @ (Builtin::Let | Builtin::Letrec))) => self.let_form(builtin, list, scope)?,
+                    Some(Item::Builtin(Builtin::Define)) => self.define(list, scope)?,
+                    Some(Item::Builtin(Builtin::Set)) => self.set(list, scope)?,
+                    _ => {
+                        let items = list.iter().map(|item| self.item(item, scope)).collect::<Result<Vec<Item>, NmError>>()?;
+                        Some(list_from(items))
+                    }
+                };
+                Ok(match resolved {
+                    Some(resolved) => Item::List(resolved, *span),
+                    None => item.clone()
+                })
+            },
+            _ => Ok(item.clone())
+        }
+    }
+
+    fn identifier(&self, name: Symbol, span: Span, scope: &Scope) -> Result<Item, NmError> {
+        for (depth, frame) in scope.iter().enumerate() {
+            if let Some(index) = frame.iter().rposition(|local| *local == name) {
+                return Ok(Item::Local(name, Address { depth, index }, span));
+            }
+        }
+        let mut globals = self.env.globals.write().unwrap();
+        if globals.lookup(name).is_some() || self.defined.contains(&name) || (self.defining.get() && self.functions.get() > 0) {
+            return Ok(Item::Global(name, globals.slot(name), span));
+        }
+        Err(NmError::unbound(name.as_str(), span))
+    }
+
+    /// Resolves the defaults and body of a function with parameter list
+    /// `params`. Each default sees the parameters before it.
+    fn lambda(&self, params: &List<Item>, span: Span, body: &Item, scope: &Scope) -> Result<Option<(List<Item>, Item)>, NmError> {
+        if parse_params(params, span).is_err() {
+            return Ok(None);
+        }
+        self.functions.set(self.functions.get() + 1);
+        let resolved = self.lambda_parts(params, body, scope);
+        self.functions.set(self.functions.get() - 1);
+        resolved.map(Some)
+    }
+
+    fn lambda_parts(&self, params: &List<Item>, body: &Item, scope: &Scope) -> Result<(List<Item>, Item), NmError> {
+        let mut names = Vec::new();
+        let mut resolved = Vec::new();
+        for param in params.iter() {
+            match param {
+                Item::Identifier(name, _) if !name.as_str().starts_with('&') => names.push(*name),
+                Item::List(default, default_span) => {
+                    if let (Some(name_item @ Item::Identifier(name, _)), Some(value)) = (default.car(), default.cdr().car()) {
+                        let value = self.item(value, &scope.prepend(names.clone()))?;
+                        resolved.push(Item::List(replace_head(vec![name_item.clone(), value], default, 2), *default_span));
+                        names.push(*name);
+                        continue;
+                    }
+                },
+                _ => ()
+            }
+            resolved.push(param.clone());
+        }
+        let body = self.item(body, &scope.prepend(names))?;
+        Ok((list_from(resolved), body))
+    }
+
+    /// `(func (params) body)`
+    fn func(&self, list: &List<Item>, scope: &Scope) -> Result<Option<List<Item>>, NmError> {
+        let rest = list.cdr();
+        let after_params = rest.cdr();
+        let (Some(Item::List(params, params_span)), Some(body)) = (rest.car(), after_params.car()) else {
+            return Ok(None);
+        };
+        let Some((params, body)) = self.lambda(params, *params_span, body, scope)? else {
+            return Ok(None);
+        };
+        Ok(Some(replace_head(vec![Item::Builtin(Builtin::Func), Item::List(params, *params_span), body], list, 3)))
+    }
+
+    /// `(let (name value)... body)` or the same with `letrec`, with one scope
+    /// frame for each frame it adds.
+    fn let_form(&self, builtin: &Builtin, list: &List<Item>, scope: &Scope) -> Result<Option<List<Item>>, NmError> {
+        let args = list.cdr().iter().cloned().collect::<Vec<Item>>();
+        let Ok((frames, body)) = let_frames(builtin, &args, Span::default()) else {
+            return Ok(None);
+        };
+
+        let mut scope = scope.clone();
+        let mut resolved = vec![Item::Builtin(builtin.clone())];
+        for frame in frames {
+            match frame {
+                LetFrame::Values(bindings) => {
+                    for binding in bindings.iter() {
+                        resolved.push(binding.with_value(self.item(&binding.value, &scope)?));
+                    }
+                    scope = scope.prepend(bindings.iter().map(|binding| binding.name).collect());
+                },
+                LetFrame::Group(run) => {
+                    scope = scope.prepend(run.iter().map(|function| function.binding.name).collect());
+                    for function in run {
+                        let Item::List(func, func_span) = &function.binding.value else {
+                            return Ok(None);
+                        };
+                        let Some(func) = self.func(func, &scope)? else {
+                            return Ok(None);
+                        };
+                        resolved.push(function.binding.with_value(Item::List(func, *func_span)));
+                    }
+                }
+            }
+        }
+        resolved.push(self.item(&body, &scope)?);
+        Ok(Some(list_from(resolved)))
+    }
+
+    /// `(define name value)` or `(define (name params...) body)`
+    fn define(&self, list: &List<Item>, scope: &Scope) -> Result<Option<List<Item>>, NmError> {
+        let defining = self.defining.replace(true);
+        let resolved = self.define_parts(list, scope);
+        self.defining.set(defining);
+        resolved
+    }
+
+    fn define_parts(&self, list: &List<Item>, scope: &Scope) -> Result<Option<List<Item>>, NmError> {
+        let rest = list.cdr();
+        let head = match (rest.car(), rest.cdr().car()) {
+            (Some(name @ Item::Identifier(_, _)), Some(value)) => vec![name.clone(), self.item(value, scope)?],
+            (Some(Item::List(signature, signature_span)), Some(body)) => {
+                let Some(name @ Item::Identifier(_, _)) = signature.car() else {
+                    return Ok(None);
+                };
+                let Some((params, body)) = self.lambda(&signature.cdr(), *signature_span, body, scope)? else {
+                    return Ok(None);
+                };
+                vec![Item::List(params.prepend(name.clone()), *signature_span), body]
+            },
+            _ => return Ok(None)
+        };
+        let mut resolved = vec![Item::Builtin(Builtin::Define)];
+        resolved.extend(head);
+        Ok(Some(replace_head(resolved, list, 3)))
+    }
+
+    /// `(set! name value)`. The name stays as it is; `set!` looks it up when
+    /// it runs.
+    fn set(&self, list: &List<Item>, scope: &Scope) -> Result<Option<List<Item>>, NmError> {
+        let rest = list.cdr();
+        let value = rest.cdr();
+        let (Some(name @ Item::Identifier(_, _)), Some(value)) = (rest.car(), value.car()) else {
+            return Ok(None);
+        };
+        let value = self.item(value, scope)?;
+        Ok(Some(replace_head(vec![Item::Builtin(Builtin::Set), name.clone(), value], list, 3)))
+    }
+}
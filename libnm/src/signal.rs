@@ -0,0 +1,411 @@
+use std::{fmt, sync::Arc};
+
+use crate::{
+    program::{Item, Builtin, Operator, BinaryOperator, BooleanOperator, Env, Bindings, Params, Lambda},
+    list::List,
+    symbol::Symbol,
+    lexer::Span,
+    error::{NmError, ErrorKind},
+    resolver::resolve,
+    eval::{operator_arity, identity, overflow, f32_matherate, i64_matherate, f32_comparate, i64_comparate, f32_unarate, i64_unarate},
+    builtins::{func_parts, parse_params, let_frames, LetFrame}};
+
+/// The inputs of a compiled signal for one sample, in the order they were
+/// named when it was compiled.
+pub type Inputs = [f32];
+
+type Func<T> = Arc<dyn Fn(&Inputs) -> T + Send + Sync>;
+
+/// How deeply calls to nm functions are inlined before giving up. Only
+/// recursive functions get this far.
+const MAX_INLINE: usize = 64;
+
+/// An nm expression turned into Rust closures, for evaluating once per
+/// sample with only its inputs changing. Types are checked and operators
+/// chosen when it is compiled, calls to nm functions are inlined, and globals
+/// are read once, so redefining them later does not change the signal.
+///
+/// Signals cannot fail once compiled, so integer arithmetic that could divide
+/// by zero or overflow for some sample is rejected when compiling.
+pub struct CompiledSignal {
+    inputs: Vec<Symbol>,
+    func: Func<f32>,
+}
+
+impl CompiledSignal {
+    /// Compiles `program`, which may refer to `inputs` as well as to the
+    /// globals and locals of `env`. It must evaluate to a number.
+    pub fn compile(program: &Item, inputs: &[&str], env: &Env) -> Result<Self, NmError> {
+        let inputs = inputs.iter().map(|name| Symbol::intern(name)).collect::<Vec<Symbol>>();
+        let frame = inputs.iter().map(|name| (*name, Item::Nil)).collect();
+        let program = resolve(program, &env.push_frame(frame))?;
+
+        let input_nodes = (0..inputs.len()).map(|index| Node::Float(Arc::new(move |inputs: &Inputs| inputs[index]))).collect();
+        let compiler = Compiler { env };
+        let scope = compiler.scope(&env.locals).prepend(input_nodes);
+        let func = match compiler.node(&program, &scope, Span::default(), 0)? {
+            Node::Float(func) => func,
+            Node::Int(func, _) => Arc::new(move |inputs: &Inputs| func(inputs) as f32),
+            other => return Err(NmError::type_mismatch(format!("a signal must be a number, got {:?}", other), program_span(&program)))
+        };
+        Ok(CompiledSignal { inputs, func })
+    }
+
+    /// The names of the inputs, in the order `CompiledSignal::eval` expects
+    /// their values.
+    pub fn inputs(&self) -> &[Symbol] {
+        &self.inputs
+    }
+
+    /// Evaluates the signal for one sample. `inputs` must hold a value for
+    /// every input.
+    pub fn eval(&self, inputs: &Inputs) -> f32 {
+        (self.func)(inputs)
+    }
+}
+
+fn program_span(program: &Item) -> Span {
+    match program {
+        Item::List(_, span) | Item::Identifier(_, span) | Item::Local(_, _, span) | Item::Global(_, _, span) => *span,
+        _ => Span::default()
+    }
+}
+
+/// A function written in nm, inlined wherever the signal calls it.
+struct Inline {
+    name: Option<Symbol>,
+    params: Params,
+    body: Item,
+}
+
+/// Part of a signal being compiled, with its type already known.
+#[derive(Clone)]
+enum Node {
+    Float(Func<f32>),
+    /// An integer, along with the smallest and largest values it can take.
+    Int(Func<i64>, (i64, i64)),
+    Bool(Func<bool>),
+    /// A function, along with the frames it was created in and the run of
+    /// `let`-bound functions it belongs to, if any.
+    Function(Arc<Inline>, Scope, Option<Arc<Vec<Arc<Inline>>>>),
+    /// Any other value, known while compiling.
+    Value(Item),
+}
+
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Float(_) => f.write_str("a float"),
+            Node::Int(_, _) => f.write_str("an integer"),
+            Node::Bool(_) => f.write_str("a boolean"),
+            Node::Function(_, _, _) => f.write_str("a function"),
+            Node::Value(item) => write!(f, "{:?}", item),
+        }
+    }
+}
+
+/// The nodes bound by each frame `eval` would build, innermost first.
+type Scope = List<Vec<Node>>;
+
+struct Compiler<'a> {
+    env: &'a Env,
+}
+
+impl<'a> Compiler<'a> {
+    fn scope(&self, bindings: &Bindings) -> Scope {
+        let frames = bindings.iter().collect::<Vec<_>>();
+        frames.into_iter().rev().fold(List::new(), |scope: Scope, frame| {
+            scope.prepend(frame.iter().map(|(_, value)| self.value(value)).collect())
+        })
+    }
+
+    fn value(&self, value: &Item) -> Node {
+        match value {
+            Item::Number(num) => {
+                let num = *num;
+                Node::Int(Arc::new(move |_: &Inputs| num), (num, num))
+            },
+            Item::Float(num) => {
+                let num = *num;
+                Node::Float(Arc::new(move |_: &Inputs| num))
+            },
+            Item::Boolean(b) => {
+                let b = *b;
+                Node::Bool(Arc::new(move |_: &Inputs| b))
+            },
+            Item::Function(closure) => {
+                let inline = |lambda: &Lambda| Arc::new(Inline { name: lambda.name, params: lambda.params.clone(), body: lambda.body.clone() });
+                let group = closure.group.as_ref().map(|group| Arc::new(group.members.iter().map(|member| inline(member)).collect()));
+                Node::Function(inline(&closure.lambda), self.scope(&closure.lambda.env), group)
+            },
+            other => Node::Value(other.clone())
+        }
+    }
+
+    fn node(&self, item: &Item, scope: &Scope, span: Span, depth: usize) -> Result<Node, NmError> {
+        match item {
+            Item::Local(name, address, span) => {
+                scope.iter().nth(address.depth)
+                    .and_then(|frame| frame.get(address.index))
+                    .cloned()
+                    .ok_or_else(|| NmError::unbound(name.as_str(), *span))
+            },
+            Item::Global(name, slot, span) => {
+                self.env.global(*slot)
+                    .map(|value| self.value(&value))
+                    .ok_or_else(|| NmError::unbound(name.as_str(), *span))
+            },
+            Item::Identifier(name, span) => Err(NmError::unbound(name.as_str(), *span)),
+            Item::List(list, span) => self.list(list, scope, *span, depth),
+            _ => Ok(self.value(item))
+        }
+        .map_err(|mut err| {
+            err.span.get_or_insert(span);
+            err
+        })
+    }
+
+    fn list(&self, list: &List<Item>, scope: &Scope, span: Span, depth: usize) -> Result<Node, NmError> {
+        let Some(head) = list.car() else {
+            return Ok(Node::Value(Item::Nil));
+        };
+        match head {
+            Item::Operator(op) => {
+                let operands = list.cdr().iter()
+                    .map(|operand| self.node(operand, scope, span, depth))
+                    .collect::<Result<Vec<Node>, NmError>>()?;
+                operator(op, operands, span)
+            },
+            Item::Builtin(Builtin::If) => self.if_form(list, scope, span, depth),
+            Item::Builtin(builtin @ (Builtin::Let | Builtin::Letrec)) => self.let_form(builtin, list, scope, span, depth),
+            // parsing wraps every program in a progn; anything but its last
+            // form could only matter for its side effects, which signals lack
+            Item::Builtin(Builtin::Progn) => {
+                let forms = list.cdr().iter().map(|form| self.node(form, scope, span, depth)).collect::<Result<Vec<Node>, NmError>>()?;
+                Ok(forms.into_iter().last().unwrap_or(Node::Value(Item::Nil)))
+            },
+            Item::Builtin(Builtin::Func) => match func_parts(&Item::List(list.clone(), span)) {
+                Some((params, params_span, body)) => {
+                    let inline = Inline { name: None, params: parse_params(&params, params_span)?, body };
+                    Ok(Node::Function(Arc::new(inline), scope.clone(), None))
+                },
+                None => Err(NmError::syntax(String::from("func needs (args) (eval)"), span))
+            },
+            Item::Builtin(builtin) => Err(NmError::unsupported(format!("{:?}", builtin).to_lowercase(), span)),
+            _ => {
+                let args = list.cdr().iter()
+                    .map(|arg| self.node(arg, scope, span, depth))
+                    .collect::<Result<Vec<Node>, NmError>>()?;
+                match self.node(head, scope, span, depth)? {
+                    Node::Function(inline, scope, group) => self.call(&inline, scope, group, args, span, depth),
+                    other => Err(NmError::unsupported(format!("calling {:?}", other), span))
+                }
+            }
+        }
+    }
+
+    /// Inlines a call to `inline`, binding its arguments the way `bind_args`
+    /// does.
+    fn call(&self, inline: &Inline, scope: Scope, group: Option<Arc<Vec<Arc<Inline>>>>, args: Vec<Node>, span: Span, depth: usize) -> Result<Node, NmError> {
+        let function = inline.name.map_or_else(|| String::from("lambda"), |name| name.to_string());
+        if depth == MAX_INLINE {
+            return Err(NmError::unsupported(format!("calls nested more than {} deep, is {} recursive?", MAX_INLINE, function), span));
+        }
+        let params = &inline.params;
+        if args.len() < params.min() || params.max().is_some_and(|max| args.len() > max) {
+            return Err(NmError::arity(function, params.min(), params.max(), args.len(), span));
+        }
+        if params.rest.is_some() {
+            return Err(NmError::unsupported(String::from("&rest parameters"), span));
+        }
+
+        let scope = match group {
+            Some(members) => {
+                let frame = members.iter().map(|member| Node::Function(member.clone(), scope.clone(), Some(members.clone()))).collect();
+                scope.prepend(frame)
+            },
+            None => scope
+        };
+        let mut args = args.into_iter();
+        let mut frame = args.by_ref().take(params.required.len()).collect::<Vec<Node>>();
+        for (_, default) in params.optional.iter() {
+            let value = match args.next() {
+                Some(arg) => arg,
+                None => self.node(default, &scope.prepend(frame.clone()), span, depth + 1)?
+            };
+            frame.push(value);
+        }
+        self.node(&inline.body, &scope.prepend(frame), span, depth + 1)
+    }
+
+    fn if_form(&self, list: &List<Item>, scope: &Scope, span: Span, depth: usize) -> Result<Node, NmError> {
+        let args = list.cdr().iter().cloned().collect::<Vec<Item>>();
+        let [condition, then, otherwise, ..] = args.as_slice() else {
+            return Err(NmError::unsupported(String::from("if without both branches"), span));
+        };
+        let Node::Bool(condition) = self.node(condition, scope, span, depth)? else {
+            return Err(NmError::type_mismatch(String::from("if condition must be a boolean"), span));
+        };
+        // an integer branch is promoted if the other gives a float
+        let branches = match (self.node(then, scope, span, depth)?, self.node(otherwise, scope, span, depth)?) {
+            (Node::Int(then, _), otherwise @ Node::Float(_)) => (to_float(then), otherwise),
+            (then @ Node::Float(_), Node::Int(otherwise, _)) => (then, to_float(otherwise)),
+            branches => branches
+        };
+        match branches {
+            (Node::Float(then), Node::Float(otherwise)) => Ok(Node::Float(Arc::new(move |inputs: &Inputs| if condition(inputs) { then(inputs) } else { otherwise(inputs) }))),
+            (Node::Int(then, (then_min, then_max)), Node::Int(otherwise, (else_min, else_max))) => {
+                let range = (then_min.min(else_min), then_max.max(else_max));
+                Ok(Node::Int(Arc::new(move |inputs: &Inputs| if condition(inputs) { then(inputs) } else { otherwise(inputs) }), range))
+            },
+            (Node::Bool(then), Node::Bool(otherwise)) => Ok(Node::Bool(Arc::new(move |inputs: &Inputs| if condition(inputs) { then(inputs) } else { otherwise(inputs) }))),
+            (then, otherwise) => Err(NmError::type_mismatch(format!("if branches must have the same type, got {:?} and {:?}", then, otherwise), span))
+        }
+    }
+
+    /// Adds a scope frame for each frame the `let` adds, then compiles its
+    /// body.
+    fn let_form(&self, builtin: &Builtin, list: &List<Item>, scope: &Scope, span: Span, depth: usize) -> Result<Node, NmError> {
+        let args = list.cdr().iter().cloned().collect::<Vec<Item>>();
+        let (frames, body) = let_frames(builtin, &args, span)?;
+        let mut scope = scope.clone();
+        for frame in frames {
+            scope = match frame {
+                LetFrame::Values(bindings) => {
+                    let values = bindings.iter().map(|binding| self.node(&binding.value, &scope, binding.span, depth)).collect::<Result<Vec<Node>, NmError>>()?;
+                    scope.prepend(values)
+                },
+                LetFrame::Group(run) => {
+                    let members = run.into_iter().map(|function| Arc::new(Inline { name: Some(function.binding.name), params: function.params, body: function.body })).collect();
+                    bind_run(scope, members)
+                }
+            };
+        }
+        self.node(&body, &scope, span, depth)
+    }
+}
+
+/// Adds a frame for a run of `let`-bound functions, each of which sees the
+/// whole run when it is called.
+fn bind_run(scope: Scope, members: Vec<Arc<Inline>>) -> Scope {
+    let members = Arc::new(members);
+    let frame = members.iter().map(|member| Node::Function(member.clone(), scope.clone(), Some(members.clone()))).collect();
+    scope.prepend(frame)
+}
+
+/// Turns integer operands into floats if any operand is a float, the same
+/// way `eval` promotes them.
+fn promote(operands: Vec<Node>) -> Vec<Node> {
+    if !operands.iter().any(|operand| matches!(operand, Node::Float(_))) {
+        return operands;
+    }
+    operands.into_iter().map(|operand| match operand {
+        Node::Int(a, _) => to_float(a),
+        other => other
+    }).collect()
+}
+
+fn to_float(a: Func<i64>) -> Node {
+    Node::Float(Arc::new(move |inputs: &Inputs| a(inputs) as f32))
+}
+
+/// Builds `op` applied to `operands` the way `eval::apply_operator` applies
+/// it, one or two operands at a time.
+fn operator(op: &Operator, operands: Vec<Node>, span: Span) -> Result<Node, NmError> {
+    let (min, max) = operator_arity(op);
+    if operands.len() < min || max.is_some_and(|max| operands.len() > max) {
+        return Err(NmError::arity(op.to_string(), min, max, operands.len(), span));
+    }
+    let int = |num: i64| Node::Int(Arc::new(move |_: &Inputs| num), (num, num));
+    let boolean = |b: bool| Node::Bool(Arc::new(move |_: &Inputs| b));
+    match (op, operands.as_slice()) {
+        (Operator::BinaryOperator(binop), []) => Ok(int(identity(binop))),
+        (Operator::BinaryOperator(binop), [operand]) => apply(op, vec![int(identity(binop)), operand.clone()], span),
+        (Operator::BinaryOperator(_), [first, rest @ ..]) => {
+            rest.iter().try_fold(first.clone(), |node, operand| apply(op, vec![node, operand.clone()], span))
+        },
+        (Operator::BinaryComparator(_), _) => {
+            let and = Operator::BooleanOperator(BooleanOperator::And);
+            let mut pairs = operands.windows(2).map(|pair| apply(op, pair.to_vec(), span));
+            let first = pairs.next().expect("comparisons take at least two operands")?;
+            pairs.try_fold(first, |node, pair| apply(&and, vec![node, pair?], span))
+        },
+        (Operator::BooleanOperator(boolop @ (BooleanOperator::And | BooleanOperator::Or)), _) => {
+            let start = boolean(*boolop == BooleanOperator::And);
+            operands.iter().try_fold(start, |node, operand| apply(op, vec![node, operand.clone()], span))
+        },
+        _ => apply(op, operands, span)
+    }
+}
+
+/// The smallest and largest values `op` can give for operands in the ranges
+/// `a` and `b`, failing if it could divide by zero or overflow for any of
+/// them. Constant operands fail the same way they do in `eval`.
+fn int_range(op: &BinaryOperator, a: (i64, i64), b: (i64, i64), span: Span) -> Result<(i64, i64), NmError> {
+    let constant = a.0 == a.1 && b.0 == b.1;
+    if *op == BinaryOperator::Div && b.0 <= 0 && b.1 >= 0 {
+        return Err(match constant {
+            true => NmError::new(ErrorKind::DivisionByZero, Some(span)),
+            false => NmError::unsupported(String::from("integer division by a value that may be zero"), span)
+        });
+    }
+    // every operator is at its smallest and largest at the corners of the
+    // ranges, once division by a range containing zero is ruled out
+    let func = i64_matherate(op);
+    let corners = [(a.0, b.0), (a.0, b.1), (a.1, b.0), (a.1, b.1)].map(|(x, y)| func(x, y));
+    match corners {
+        [Some(w), Some(x), Some(y), Some(z)] => Ok((w.min(x).min(y).min(z), w.max(x).max(y).max(z))),
+        _ if constant => Err(overflow(op, a.0, b.0, span)),
+        _ => Err(NmError::unsupported(String::from("integer arithmetic that may overflow"), span))
+    }
+}
+
+fn apply(op: &Operator, operands: Vec<Node>, span: Span) -> Result<Node, NmError> {
+    let mismatch = |operands: &[Node]| NmError::type_mismatch(format!("arguments {:?} are not suitable for {}", operands, op), span);
+    let operands = promote(operands);
+    let node = match (op, operands.as_slice()) {
+        (Operator::BinaryOperator(binop), [Node::Int(a, a_range), Node::Int(b, b_range)]) => {
+            let range = int_range(binop, *a_range, *b_range, span)?;
+            if range.0 == range.1 {
+                let num = range.0;
+                return Ok(Node::Int(Arc::new(move |_: &Inputs| num), range));
+            }
+            let (a, b, func) = (a.clone(), b.clone(), i64_matherate(binop));
+            Node::Int(Arc::new(move |inputs: &Inputs| func(a(inputs), b(inputs)).expect("the range of the operands rules out failure")), range)
+        },
+        (Operator::BinaryOperator(binop), [Node::Float(a), Node::Float(b)]) => {
+            let (a, b, func) = (a.clone(), b.clone(), f32_matherate(binop));
+            Node::Float(Arc::new(move |inputs: &Inputs| func(a(inputs), b(inputs))))
+        },
+        (Operator::BinaryComparator(comp), [Node::Int(a, _), Node::Int(b, _)]) => {
+            let (a, b, func) = (a.clone(), b.clone(), i64_comparate(comp));
+            Node::Bool(Arc::new(move |inputs: &Inputs| func(a(inputs), b(inputs))))
+        },
+        (Operator::BinaryComparator(comp), [Node::Float(a), Node::Float(b)]) => {
+            let (a, b, func) = (a.clone(), b.clone(), f32_comparate(comp));
+            Node::Bool(Arc::new(move |inputs: &Inputs| func(a(inputs), b(inputs))))
+        },
+        (Operator::UnaryOperator(unop), [Node::Int(a, _)]) => {
+            let (a, func) = (a.clone(), i64_unarate(unop));
+            Node::Float(Arc::new(move |inputs: &Inputs| func(a(inputs))))
+        },
+        (Operator::UnaryOperator(unop), [Node::Float(a)]) => {
+            let (a, func) = (a.clone(), f32_unarate(unop));
+            Node::Float(Arc::new(move |inputs: &Inputs| func(a(inputs))))
+        },
+        (Operator::BooleanOperator(BooleanOperator::Not), [Node::Bool(a)]) => {
+            let a = a.clone();
+            Node::Bool(Arc::new(move |inputs: &Inputs| !a(inputs)))
+        },
+        (Operator::BooleanOperator(BooleanOperator::And), [Node::Bool(a), Node::Bool(b)]) => {
+            let (a, b) = (a.clone(), b.clone());
+            Node::Bool(Arc::new(move |inputs: &Inputs| a(inputs) && b(inputs)))
+        },
+        (Operator::BooleanOperator(BooleanOperator::Or), [Node::Bool(a), Node::Bool(b)]) => {
+            let (a, b) = (a.clone(), b.clone());
+            Node::Bool(Arc::new(move |inputs: &Inputs| a(inputs) || b(inputs)))
+        },
+        _ => return Err(mismatch(&operands))
+    };
+    Ok(node)
+}
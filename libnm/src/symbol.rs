@@ -0,0 +1,64 @@
+use std::{collections::HashMap, fmt, sync::{OnceLock, RwLock}};
+
+/// An interned identifier. Two symbols are equal exactly when they were
+/// interned from the same text, so comparing them is an integer comparison.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+/// Every name ever interned. Names are leaked so that `as_str` can hand out
+/// `&'static str`; programs only ever use a bounded set of identifiers.
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(Default::default)
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        if let Some(symbol) = interner().read().unwrap().ids.get(name) {
+            return *symbol;
+        }
+        let mut interner = interner().write().unwrap();
+        if let Some(symbol) = interner.ids.get(name) {
+            return *symbol;
+        }
+        let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+        let symbol = Symbol(interner.names.len() as u32);
+        interner.names.push(name);
+        interner.ids.insert(name, symbol);
+        symbol
+    }
+
+    pub fn as_str(self) -> &'static str {
+        interner().read().unwrap().names[self.0 as usize]
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
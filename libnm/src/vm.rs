@@ -0,0 +1,253 @@
+use std::{io, sync::Arc};
+
+use crate::{
+    program::{Item, Env, Closure, Lambda, RecGroup},
+    list::List,
+    symbol::Symbol,
+    parser::parse,
+    resolver::resolve,
+    lexer::{lex, Span},
+    error::{NmError, ErrorKind, StackFrame},
+    eval::{eval, apply_operator, decides, bind_group, enter, call_native},
+    builtins::named,
+    block,
+    compiler::{compile, Chunk, Op}};
+
+/// A function the VM is running: its code, how far it has got, the
+/// environment its body sees and where its values start on the stack.
+struct CallFrame {
+    chunk: Arc<Chunk>,
+    ip: usize,
+    env: Env,
+    base: usize,
+    /// The call that started this function, or `None` for the program itself.
+    call: Option<StackFrame>,
+}
+
+struct Vm {
+    stack: Vec<Item>,
+    /// The functions waiting for the running one to return, outermost first.
+    callers: Vec<CallFrame>,
+    current: CallFrame,
+}
+
+/// Runs compiled code in `env`. Calls between nm functions do not use the
+/// Rust stack, so recursion is limited only by memory.
+pub fn run(chunk: Arc<Chunk>, env: &Env) -> Result<Item, NmError> {
+    let current = CallFrame { chunk, ip: 0, env: env.clone(), base: 0, call: None };
+    let mut vm = Vm { stack: Vec::new(), callers: Vec::new(), current };
+    vm.execute().map_err(|mut err| {
+        let frames = std::iter::once(vm.current).chain(vm.callers.into_iter().rev());
+        err.trace.extend(frames.filter_map(|frame| frame.call));
+        err
+    })
+}
+
+/// Like [`eval_string`](crate::eval::eval_string), but compiles the program
+/// and runs it on the VM.
+pub fn run_string(program_string: &str, env: Env) -> Result<Item, NmError> {
+    let tokens = lex(program_string)?;
+    let prog = resolve(&parse(tokens)?, &env)?;
+    run(Arc::new(compile(&prog)), &env)
+}
+
+fn closure(lambda: Lambda) -> Item {
+    Item::Function(Closure { lambda: Arc::new(lambda), group: None })
+}
+
+impl Vm {
+    fn pop(&mut self) -> Item {
+        self.stack.pop().expect("compiled code keeps the stack balanced")
+    }
+
+    fn pop_n(&mut self, count: usize) -> Vec<Item> {
+        self.stack.split_off(self.stack.len() - count)
+    }
+
+    fn execute(&mut self) -> Result<Item, NmError> {
+        loop {
+            let chunk = &self.current.chunk;
+            let (op, span) = (chunk.code[self.current.ip].clone(), chunk.spans[self.current.ip]);
+            self.current.ip += 1;
+
+            match op {
+                Op::Const(index) => self.stack.push(self.current.chunk.constants[index].clone()),
+                Op::Local(name, address) => {
+                    let value = self.current.env.local(address).ok_or_else(|| NmError::unbound(name.as_str(), span))?;
+                    self.stack.push(value);
+                },
+                Op::Global(name, slot) => {
+                    let value = self.current.env.global(slot).ok_or_else(|| NmError::unbound(name.as_str(), span))?;
+                    self.stack.push(value);
+                },
+                Op::Name(name) => {
+                    let value = self.current.env.lookup(name).ok_or_else(|| NmError::unbound(name.as_str(), span))?;
+                    self.stack.push(value);
+                },
+                Op::Pop => { self.pop(); },
+                Op::Operator(op, count) => {
+                    let operands = self.pop_n(count);
+                    self.stack.push(apply_operator(&op, &operands, span)?);
+                },
+                Op::Call(count, name) => self.call(count, name, span, false)?,
+                Op::TailCall(count, name) => self.call(count, name, span, true)?,
+                Op::Return => {
+                    let value = self.pop();
+                    self.stack.truncate(self.current.base);
+                    match self.callers.pop() {
+                        Some(caller) => self.current = caller,
+                        None => return Ok(value)
+                    }
+                    self.stack.push(value);
+                },
+                Op::Jump(to) => self.current.ip = to,
+                Op::JumpIfDecided(to, op) => if decides(&op, self.stack.last().expect("and and or start from a value")) {
+                    self.current.ip = to;
+                },
+                Op::JumpUnless(to, branches) => match self.pop() {
+                    Item::Boolean(true) => (),
+                    Item::Boolean(false) => self.current.ip = to,
+                    Item::Bools(condition) => {
+                        let Item::ListLiteral(branches) = &self.current.chunk.constants[branches] else {
+                            unreachable!("the compiler stores both branches of an if")
+                        };
+                        let mut branches = branches.iter();
+                        let (Some(then), Some(otherwise)) = (branches.next(), branches.next()) else {
+                            unreachable!("the compiler stores both branches of an if")
+                        };
+                        let value = block::select(&condition, then, otherwise, &self.current.env, span)?;
+                        self.stack.push(value);
+                        self.current.ip = to - 1;
+                    },
+                    other => return Err(NmError::type_mismatch(format!("if condition must be a boolean, got {:?}", other), span))
+                },
+                Op::Closure(index) => {
+                    let template = &self.current.chunk.templates[index];
+                    self.stack.push(closure(Lambda {
+                        name: template.name,
+                        params: template.params.clone(),
+                        body: template.body.clone(),
+                        env: self.current.env.locals.clone(),
+                        code: template.code.clone(),
+                    }));
+                },
+                Op::Group(start, end) => {
+                    let env = &self.current.env;
+                    let members = self.current.chunk.templates[start..end].iter().map(|template| Arc::new(Lambda {
+                        name: template.name,
+                        params: template.params.clone(),
+                        body: template.body.clone(),
+                        env: env.locals.clone(),
+                        code: template.code.clone(),
+                    })).collect();
+                    self.current.env = env.with_locals(bind_group(&env.locals, &Arc::new(RecGroup { members })));
+                },
+                Op::Bind(names) => {
+                    let values = self.pop_n(names.len());
+                    let frame = names.into_iter().zip(values).map(|(name, value)| (name, named(value, name))).collect();
+                    self.current.env = self.current.env.push_frame(frame);
+                },
+                Op::Unbind(count) => {
+                    let locals = (0..count).fold(self.current.env.locals.clone(), |locals, _| locals.cdr());
+                    self.current.env = self.current.env.with_locals(locals);
+                },
+                Op::Print => {
+                    match self.pop() {
+                        Item::String(printout) => println!("{}", printout),
+                        res => println!("{:?}", res),
+                    }
+                    self.stack.push(Item::Nil);
+                },
+                Op::Input => {
+                    let mut buffer = String::new();
+                    io::stdin().read_line(&mut buffer).map_err(|err| {
+                        let mut err = NmError::from(err);
+                        err.span = Some(span);
+                        err
+                    })?;
+                    self.stack.push(Item::String(buffer));
+                },
+                Op::CheckString => match self.stack.last() {
+                    Some(Item::String(_)) => (),
+                    other => return Err(NmError::type_mismatch(format!("cat takes two strings as an argument! {:?} was supplied", other.unwrap_or(&Item::Nil)), span))
+                },
+                Op::Cat => {
+                    let strings = self.pop_n(2);
+                    if let [Item::String(first), Item::String(second)] = strings.as_slice() {
+                        self.stack.push(Item::String(format!("{}{}", first, second)));
+                    }
+                },
+                Op::Raise(count) => {
+                    let message = self.pop_n(count).into_iter().map(|value| match value {
+                        Item::String(s) => s,
+                        other => format!("{:?}", other)
+                    }).collect::<Vec<String>>();
+                    return Err(NmError::new(ErrorKind::User(message.join(" ")), Some(span)));
+                },
+                Op::Define(name) => {
+                    let value = named(self.pop(), name);
+                    self.current.env.define(name, value);
+                    self.stack.push(Item::Nil);
+                },
+                Op::CheckGlobal(name) => if self.current.env.is_local(name) {
+                    return Err(NmError::syntax(format!("set! can only change globals, {} is a local binding", name), span));
+                },
+                Op::Set(name) => {
+                    let value = self.pop();
+                    if !self.current.env.set(name, value) {
+                        return Err(NmError::unbound(name.as_str(), span));
+                    }
+                    self.stack.push(Item::Nil);
+                },
+                Op::Eval(index) => {
+                    let value = eval(&self.current.chunk.constants[index], &self.current.env)?;
+                    self.stack.push(value);
+                },
+            }
+        }
+    }
+
+    /// Calls the value below the top `count` values, mirroring what `eval`
+    /// does with the head of a list.
+    fn call(&mut self, count: usize, name: Option<Symbol>, span: Span, tail: bool) -> Result<(), NmError> {
+        let args = self.pop_n(count);
+        match self.pop() {
+            Item::Function(closure) => {
+                let lambda = &closure.lambda;
+                let function = match (lambda.name, name) {
+                    (Some(name), _) | (None, Some(name)) => name.to_string(),
+                    _ => String::from("lambda")
+                };
+                let frame = StackFrame { function, args, span };
+                let env = match enter(&closure, &frame, &self.current.env) {
+                    Ok(env) => env,
+                    Err(mut err) => {
+                        err.trace.push(frame);
+                        return Err(err);
+                    }
+                };
+                let chunk = lambda.code.get_or_init(|| Arc::new(compile(&lambda.body))).clone();
+                let callee = CallFrame { chunk, ip: 0, env, base: self.stack.len(), call: Some(frame) };
+                if tail {
+                    self.current = callee;
+                }
+                else {
+                    self.callers.push(std::mem::replace(&mut self.current, callee));
+                }
+            },
+            Item::NativeFn(native) => {
+                let value = call_native(&native, args, span)?;
+                self.stack.push(value);
+            },
+            Item::Operator(op) => {
+                let value = apply_operator(&op, &args, span)?;
+                self.stack.push(value);
+            },
+            head => {
+                let list = args.into_iter().rev().fold(List::new(), |list, item| list.prepend(item));
+                self.stack.push(Item::ListLiteral(list.prepend(head)));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,40 @@
 use std::env::{self};
-use std::fs;
 
 use libnm::eval::{eval_string, default_env};
-use libnm::lexer::lex;
-use libnm::parser::parse;
+use libnm::interpreter::Interpreter;
+use libnm::parser::parse_string;
+use libnm::optimizer::dump;
+use libnm::error::NmError;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if args.len() > 2 && args[1] == "--optimized" {
+        let nm = Interpreter::new();
+        let program = std::fs::read_to_string(&args[2]).map_err(NmError::from).and_then(parse_string);
+        match program {
+            Ok(program) => println!("{}", dump(&nm.optimize(&program, &[]))),
+            Err(err) => println!("Error: {}", err)
+        }
+        return;
+    }
     if args.len() > 1 {
         let file_name = args.get(1).unwrap();
-        match eval_string(&fs::read_to_string(file_name).unwrap(), default_env()) {
+        match Interpreter::new().load_file(file_name) {
             Ok(result) => println!("[result] {:?}", result),
-            Err(msg) => println!("Error: {}", msg)
+            Err(err) => {
+                println!("Error: {}", err);
+                if !err.trace.is_empty() {
+                    print!("Backtrace:\n{}", err.backtrace());
+                }
+            }
         }
         return;
     }
 
     println!("=-=-=-=-=-=-=-=");
-    println!("{:?}", parse(lex(&format!("(4.3 2 12 (5 6))"))).expect("can't parse"));
-    println!("{:?}", parse(lex(&format!("(nil nil)"))).expect("can't parse"));
-    println!("{:?}", parse(lex(&format!("(5 (nil nil) (1 2) nil)"))).expect("can't parse"));
+    println!("{:?}", parse_string(format!("(4.3 2 12 (5 6))")).expect("can't parse"));
+    println!("{:?}", parse_string(format!("(nil nil)")).expect("can't parse"));
+    println!("{:?}", parse_string(format!("(5 (nil nil) (1 2) nil)")).expect("can't parse"));
 
     println!("{:?}", eval_string(&format!("(* e 2.0)"), default_env()).unwrap());
     println!("{:?}", eval_string(&format!("(if (< 3 2) (* 2 4) (+ 1 5))"), default_env()).unwrap());
@@ -27,15 +42,15 @@ fn main() {
     println!("{:?}", eval_string(&format!("(== (* 3.1 2.4) (* 2.4 3.1))"), default_env()).unwrap());
     //println!("{:?}", eval_string(&format!("(print (input))"), default_env()).unwrap());
     let fact_program = 
-        "(let 
+        "(letrec 
             (fac (func (x) 
                 (if (<= x 1) 1 (* x (fac (- x 1))))))
             (fac 12)
         )";
-    //println!("parsed: {:?}", parse(lex(&format!("{}", fact_program))).expect("can't parse"));
+    //println!("parsed: {:?}", parse_string(format!("{}", fact_program)).expect("can't parse"));
     println!("{:?}", eval_string(&format!("{}", fact_program), default_env()).unwrap());
 
     let cat_program = "(print (cat \"Hello, \" \"World!\"))";
-    println!("parsed: {:?}", parse(lex(&format!("{}", cat_program))).expect("can't parse"));
+    println!("parsed: {:?}", parse_string(format!("{}", cat_program)).expect("can't parse"));
     println!("{:?}", eval_string(&format!("{}", cat_program), default_env()).unwrap());
 }
@@ -1,10 +1,13 @@
 use std::{sync::{Arc, Mutex}, f32::consts::PI};
 use player::{Player, PlayerBuffer};
 use threadpool::ThreadPool;
-use libnm::{parser::{parse, parse_string}, eval::{eval, default_env}, list::List, program::Item};
+use libnm::{parser::{parse, parse_string}, interpreter::Interpreter, program::Item};
 
 pub mod player;
 
+/// How many samples are evaluated at once.
+const BLOCK_SIZE: usize = 256;
+
 fn main() {
 
 
@@ -12,10 +15,16 @@ fn main() {
     let pool = ThreadPool::new(32);
     let time_mut = Arc::new(Mutex::new(0 as usize));
 
+    let nm = Arc::new(Interpreter::new());
+    nm.define_native("f", Item::Float(440.0));
     let func = match parse_string(format!("(* (sin (* t f)) 0.5)")) {
         Ok(item) => item,
         _ => panic!("Could not parse string!")
     };
+    let func = match nm.resolve(&nm.optimize(&func, &["t"]), &["t"]) {
+        Ok(item) => item,
+        Err(err) => panic!("{}", err)
+    };
     let p: Player = Player::new();
 
     let buffer_len = p.sample_rate() * 3;
@@ -27,34 +36,31 @@ fn main() {
         let sample_rate = p.sample_rate();
         let buf_mut = buf_mutex.clone();
         let func_clone = func.clone();
+        let nm = nm.clone();
 
         pool.execute(move || {
             loop {
-                let mut i = time_c.lock().unwrap();
-                if *i % (buffer_len / 10) == 0 {
-                    println!("{}%", *i / (buffer_len / 100));
-                }
+                let start = {
+                    let mut i = time_c.lock().unwrap();
+                    if *i >= buffer_len {
+                        break;
+                    }
+                    let start = *i;
+                    *i = (start + BLOCK_SIZE).min(buffer_len);
+                    if start / (buffer_len / 10) != *i / (buffer_len / 10) {
+                        println!("{}%", *i / (buffer_len / 100));
+                    }
+                    start
+                };
+                let end = (start + BLOCK_SIZE).min(buffer_len);
 
-                *i = *i + 1;
-                //println!("{}/{}", *i, buffer_len);
-                if *i >= buffer_len {
-                    break;
+                let times = (start..end).map(|i| i as f32 / sample_rate as f32).collect::<Vec<f32>>();
+                let mut block = vec![0.0; end - start];
+                if let Err(err) = nm.eval_block(&func_clone, &[("t", &times)], &mut block) {
+                    panic!("{}", err)
                 }
-                let time = *i as f32 / sample_rate as f32;
-
-                let mut env = default_env();
-                env = env.prepend(("f", Item::Float(440.0)));
-                env = env.prepend(("t", Item::Float(time)));
-
-                let val = match eval(&func_clone, &env){
-                    Ok(Item::Float(val)) => val,
-                    Ok(_) => panic!("Error, value is not a float"),
-                    Err(msg) => panic!("{}", msg.as_str())
-                };
 
-                //while !buf_mut.lock().unwrap().should_write() { }
-                let index : usize = *i as usize;
-                buf_mut.lock().unwrap()[index] = val;
+                buf_mut.lock().unwrap()[start..end].copy_from_slice(&block);
             }
         })
     }